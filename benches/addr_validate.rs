@@ -1,7 +1,7 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use pprof::validate;
+use pprof::{validate, Validator};
 
 fn bench_validate_addr(c: &mut Criterion) {
     c.bench_function("validate stack addr", |b| {
@@ -25,5 +25,38 @@ fn bench_validate_addr(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_validate_addr);
+fn bench_validators(c: &mut Criterion) {
+    let validators = [
+        Validator::Pipe,
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        Validator::ProcessVmReadv,
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        Validator::MapsSnapshot,
+    ];
+
+    let heap_addrs = vec![0; 100];
+    let mut group = c.benchmark_group("validator");
+    for validator in validators {
+        validator.prepare();
+
+        group.bench_function(format!("{:?} heap addr", validator), |b| {
+            b.iter(|| {
+                heap_addrs.iter().for_each(|item| {
+                    validator.validate(item as *const _ as *const libc::c_void);
+                })
+            })
+        });
+
+        group.bench_function(format!("{:?} invalid addr", validator), |b| {
+            b.iter(|| {
+                (1..=100).for_each(|item: usize| {
+                    validator.validate(item.wrapping_neg() as *const libc::c_void);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_validate_addr, bench_validators);
 criterion_main!(benches);
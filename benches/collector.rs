@@ -16,7 +16,7 @@ fn bench_write_to_collector(c: &mut Criterion) {
 
         b.iter(|| {
             vec.iter().for_each(|item| {
-                collector.add(*item, 1).unwrap();
+                collector.add(item, 1).unwrap();
             })
         })
     });
@@ -33,7 +33,7 @@ fn bench_write_to_collector(c: &mut Criterion) {
 
         b.iter(|| {
             vec.iter().for_each(|item| {
-                collector.add(*item, 1);
+                collector.add(item, 1, |_| {});
             })
         });
     });
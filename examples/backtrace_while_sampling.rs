@@ -13,7 +13,7 @@ fn deep_recursive(depth: i32) {
 fn main() {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(1000)
-        .blocklist(&["libc", "libgcc", "pthread"])
+        .blocklist(&["libc", "libgcc"])
         .build()
         .unwrap();
 
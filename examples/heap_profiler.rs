@@ -0,0 +1,40 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fs::File;
+
+use pprof::heap::{HeapProfilerGuardBuilder, ProfiledAllocator};
+
+#[global_allocator]
+static ALLOC: ProfiledAllocator<std::alloc::System> = ProfiledAllocator::new(std::alloc::System);
+
+#[inline(never)]
+fn leak_buffers(count: usize) -> Vec<Vec<u8>> {
+    (0..count).map(|i| vec![i as u8; 4096]).collect()
+}
+
+#[inline(never)]
+fn temporary_buffers(count: usize) -> usize {
+    let mut total = 0;
+    for i in 0..count {
+        let buffer = vec![i as u8; 16384];
+        total += buffer.iter().map(|v| *v as usize).sum::<usize>();
+    }
+    total
+}
+
+fn main() {
+    let guard = HeapProfilerGuardBuilder::default()
+        .sample_interval(64 * 1024)
+        .build()
+        .unwrap();
+
+    let kept = leak_buffers(10000);
+    let total = temporary_buffers(10000);
+    println!("kept {} buffers, sum {}", kept.len(), total);
+
+    let report = guard.report().unwrap();
+    println!("{:?}", report);
+
+    let file = File::create("heap.svg").unwrap();
+    report.flamegraph(file).unwrap();
+}
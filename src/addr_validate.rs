@@ -1,6 +1,6 @@
 use std::{
     mem::size_of,
-    sync::atomic::{AtomicI32, Ordering},
+    sync::atomic::{AtomicI32, AtomicU8, Ordering},
 };
 
 use nix::{
@@ -63,20 +63,97 @@ fn open_pipe() -> nix::Result<()> {
     Ok(())
 }
 
+const CHECK_LENGTH: usize = 2 * size_of::<*const libc::c_void>() / size_of::<u8>();
+
+/// The probe which checks whether an address can be read by the unwinders,
+/// e.g. a frame pointer or a return address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Validator {
+    /// Writes the memory to a pipe, which fails with `EFAULT` if it can't be
+    /// read. It keeps two file descriptors open, and fails every check if they
+    /// can't be created, e.g. because of the limit of a sandboxed process.
+    #[default]
+    Pipe,
+
+    /// Reads the memory with `process_vm_readv` on the own process, which
+    /// needs no file descriptor. It may be denied by a seccomp policy.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    ProcessVmReadv,
+
+    /// Looks the address up in a snapshot of the readable mappings of
+    /// `/proc/self/maps`, without any system call in the signal handler. The
+    /// snapshot is taken when the profiler starts, and again by
+    /// `ProfilerGuard::refresh_modules` or [`Validator::prepare`].
+    ///
+    /// The memory mapped after the snapshot is not readable to the unwinders,
+    /// and the memory unmapped after it is still taken as readable, so it
+    /// should be refreshed after the mappings changed, e.g. when a library
+    /// was unloaded.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    MapsSnapshot,
+}
+
+static VALIDATOR: AtomicU8 = AtomicU8::new(Validator::Pipe as u8);
+
+impl Validator {
+    /// Returns the validator used by [`validate`].
+    pub(crate) fn current() -> Validator {
+        match VALIDATOR.load(Ordering::Relaxed) {
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            n if n == Validator::ProcessVmReadv as u8 => Validator::ProcessVmReadv,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            n if n == Validator::MapsSnapshot as u8 => Validator::MapsSnapshot,
+            _ => Validator::Pipe,
+        }
+    }
+
+    /// Makes it the validator used by [`validate`], once it's prepared.
+    pub(crate) fn select(self) {
+        self.prepare();
+        VALIDATOR.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Prepares the validator outside of the signal handler: it takes a new
+    /// snapshot of the mappings for `MapsSnapshot`, and does nothing for the
+    /// other ones.
+    pub fn prepare(self) {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if self == Validator::MapsSnapshot {
+            maps::refresh();
+        }
+    }
+
+    /// Tells whether the memory at `addr` can be read. It's safe to call in a
+    /// signal handler.
+    pub fn validate(self, addr: *const libc::c_void) -> bool {
+        // it's a short circuit for null pointer, as it'll give an error in
+        // `std::slice::from_raw_parts` if the pointer is null.
+        if addr.is_null() {
+            return false;
+        }
+
+        match self {
+            Validator::Pipe => validate_pipe(addr),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Validator::ProcessVmReadv => validate_process_vm_readv(addr),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            Validator::MapsSnapshot => maps::validate(addr as usize),
+        }
+    }
+}
+
+/// Tells whether the memory at `addr` can be read, with the validator selected
+/// by `ProfilerGuardBuilder::validator`. It's safe to call in a signal handler.
+pub fn validate(addr: *const libc::c_void) -> bool {
+    Validator::current().validate(addr)
+}
+
 // validate whether the address `addr` is readable through `write()` to a pipe
 //
 // if the second argument of `write(ptr, buf)` is not a valid address, the
 // `write()` will return an error the error number should be `EFAULT` in most
 // cases, but we regard all errors (except EINTR) as a failure of validation
-pub fn validate(addr: *const libc::c_void) -> bool {
-    // it's a short circuit for null pointer, as it'll give an error in
-    // `std::slice::from_raw_parts` if the pointer is null.
-    if addr.is_null() {
-        return false;
-    }
-
-    const CHECK_LENGTH: usize = 2 * size_of::<*const libc::c_void>() / size_of::<u8>();
-
+fn validate_pipe(addr: *const libc::c_void) -> bool {
     // read data in the pipe
     let read_fd = MEM_VALIDATE_PIPE.read_fd.load(Ordering::SeqCst);
     let valid_read = loop {
@@ -106,6 +183,109 @@ pub fn validate(addr: *const libc::c_void) -> bool {
     }
 }
 
+// validate whether the address `addr` is readable by copying it with
+// `process_vm_readv`, which fails with `EFAULT` if it's not
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn validate_process_vm_readv(addr: *const libc::c_void) -> bool {
+    let mut buf = [0u8; CHECK_LENGTH];
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: CHECK_LENGTH,
+    };
+    let remote = libc::iovec {
+        iov_base: addr as *mut libc::c_void,
+        iov_len: CHECK_LENGTH,
+    };
+
+    let bytes = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+    bytes == CHECK_LENGTH as isize
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod maps {
+    use spin::RwLock;
+
+    use super::CHECK_LENGTH;
+
+    // the readable address ranges, sorted, and merged with the adjacent ones
+    static MAPS: RwLock<Vec<(usize, usize)>> = RwLock::new(Vec::new());
+
+    pub fn refresh() {
+        let maps = match std::fs::read_to_string("/proc/self/maps") {
+            Ok(maps) => parse(&maps),
+            Err(err) => {
+                log::warn!("fail to read /proc/self/maps: {}", err);
+                Vec::new()
+            }
+        };
+
+        // the previous snapshot is freed after the lock is released
+        let _previous = std::mem::replace(&mut *MAPS.write(), maps);
+    }
+
+    // parses the lines like `7f1c2d4e5000-7f1c2d4e7000 r-xp 00000000 08:01 1234 /lib/libc.so.6`
+    fn parse(maps: &str) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let (range, perms) = match (fields.next(), fields.next()) {
+                (Some(range), Some(perms)) => (range, perms),
+                _ => continue,
+            };
+            if !perms.starts_with('r') {
+                continue;
+            }
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => continue,
+            };
+            let (start, end) = match (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            ) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => continue,
+            };
+
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+
+    pub fn validate(addr: usize) -> bool {
+        // the snapshot is being refreshed
+        let maps = match MAPS.try_read() {
+            Some(maps) => maps,
+            None => return false,
+        };
+
+        let index = maps.partition_point(|&(start, _)| start <= addr);
+        match index.checked_sub(1).and_then(|index| maps.get(index)) {
+            Some(&(_, end)) => addr < end && CHECK_LENGTH <= end - addr,
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_maps() {
+            let maps = parse(
+                "1000-2000 r-xp 00000000 08:01 1234 /bin/a\n\
+                 2000-3000 rw-p 00000000 00:00 0\n\
+                 3000-4000 ---p 00000000 00:00 0\n\
+                 5000-6000 r--p 00000000 00:00 0 [vvar]\n",
+            );
+            assert_eq!(maps, vec![(0x1000, 0x3000), (0x5000, 0x6000)]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -131,4 +311,29 @@ mod test {
         assert!(!validate(std::ptr::null::<libc::c_void>()));
         assert!(!validate(-1_i32 as usize as *const libc::c_void))
     }
+
+    #[test]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn validators() {
+        let i = 0;
+        let vec = vec![0; 1000];
+        Validator::MapsSnapshot.prepare();
+        for validator in [
+            Validator::Pipe,
+            Validator::ProcessVmReadv,
+            Validator::MapsSnapshot,
+        ] {
+            // `process_vm_readv` may be denied in the sandbox which runs the tests
+            if validator == Validator::ProcessVmReadv
+                && !validator.validate(&i as *const _ as *const libc::c_void)
+            {
+                continue;
+            }
+
+            assert!(validator.validate(&i as *const _ as *const libc::c_void));
+            assert!(validator.validate(vec.as_ptr() as *const libc::c_void));
+            assert!(!validator.validate(std::ptr::null::<libc::c_void>()));
+            assert!(!validator.validate(-1_i32 as usize as *const libc::c_void));
+        }
+    }
 }
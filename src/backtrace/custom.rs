@@ -0,0 +1,313 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of, MaybeUninit};
+
+use libc::c_void;
+
+use super::{Frame, FrameImpl, Trace, UnwindMethod};
+use crate::frames::Symbol;
+
+/// The number of machine words which a frame of a custom unwinder can take, so
+/// that it's copied into the samples without allocating.
+pub const CUSTOM_FRAME_WORDS: usize = 3;
+
+type TraceFn = fn(*mut c_void, &mut dyn FnMut(&FrameImpl) -> bool);
+type ResolveSymbolFn = fn(&CustomFrame, &mut dyn FnMut(&Symbol));
+
+/// The functions of an unwinder given to `ProfilerGuardBuilder::custom_unwinder`.
+pub(crate) struct CustomUnwinder {
+    pub name: fn() -> &'static str,
+    pub init: fn(),
+    pub refresh: fn(),
+    pub trace: TraceFn,
+}
+
+impl Debug for CustomUnwinder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str((self.name)())
+    }
+}
+
+struct Custom<T>(PhantomData<T>);
+
+impl<T> Custom<T>
+where
+    T: Trace + 'static,
+    T::Frame: Frame + Copy + Send + Sync + 'static,
+{
+    const UNWINDER: &'static CustomUnwinder = &CustomUnwinder {
+        name: std::any::type_name::<T>,
+        init: T::init,
+        refresh: T::refresh,
+        trace: trace::<T>,
+    };
+}
+
+fn trace<T>(ucontext: *mut c_void, cb: &mut dyn FnMut(&FrameImpl) -> bool)
+where
+    T: Trace,
+    T::Frame: Frame + Copy + Send + Sync + 'static,
+{
+    T::trace(ucontext, |frame| {
+        cb(&FrameImpl::Custom(CustomFrame::new(*frame)))
+    })
+}
+
+impl CustomUnwinder {
+    pub fn of<T>() -> &'static CustomUnwinder
+    where
+        T: Trace + 'static,
+        T::Frame: Frame + Copy + Send + Sync + 'static,
+    {
+        Custom::<T>::UNWINDER
+    }
+}
+
+/// The functions of the frames of a custom unwinder.
+struct FrameVtable {
+    ip: fn(&CustomFrame) -> usize,
+    symbol_address: fn(&CustomFrame) -> *mut c_void,
+    resolve_symbol: ResolveSymbolFn,
+    method: fn(&CustomFrame) -> Option<UnwindMethod>,
+}
+
+/// A frame of a custom unwinder, which is copied into the words of `data`.
+#[derive(Clone, Copy)]
+pub struct CustomFrame {
+    data: [MaybeUninit<usize>; CUSTOM_FRAME_WORDS],
+    vtable: &'static FrameVtable,
+}
+
+struct Erased<F>(PhantomData<F>);
+
+impl<F: Frame + Copy + Send + Sync + 'static> Erased<F> {
+    // it fails to compile if the frame is too large
+    const FITS: () = assert!(
+        size_of::<F>() <= CUSTOM_FRAME_WORDS * size_of::<usize>()
+            && align_of::<F>() <= align_of::<usize>(),
+        "the frame of a custom unwinder must fit in `CUSTOM_FRAME_WORDS` words"
+    );
+
+    const VTABLE: &'static FrameVtable = &FrameVtable {
+        ip: ip::<F>,
+        symbol_address: symbol_address::<F>,
+        resolve_symbol: resolve_symbol::<F>,
+        method: method::<F>,
+    };
+}
+
+impl CustomFrame {
+    fn new<F: Frame + Copy + Send + Sync + 'static>(frame: F) -> CustomFrame {
+        #[allow(clippy::let_unit_value)]
+        let () = Erased::<F>::FITS;
+
+        let mut data = [MaybeUninit::uninit(); CUSTOM_FRAME_WORDS];
+        unsafe { std::ptr::write(data.as_mut_ptr() as *mut F, frame) };
+        CustomFrame {
+            data,
+            vtable: Erased::<F>::VTABLE,
+        }
+    }
+
+    /// Returns the frame, which must have been written as an `F`.
+    unsafe fn get<F: Copy>(&self) -> F {
+        std::ptr::read(self.data.as_ptr() as *const F)
+    }
+
+    pub fn ip(&self) -> usize {
+        (self.vtable.ip)(self)
+    }
+
+    pub fn symbol_address(&self) -> *mut c_void {
+        (self.vtable.symbol_address)(self)
+    }
+
+    pub fn resolve_symbol(&self, cb: &mut dyn FnMut(&Symbol)) {
+        (self.vtable.resolve_symbol)(self, cb)
+    }
+
+    pub fn method(&self) -> Option<UnwindMethod> {
+        (self.vtable.method)(self)
+    }
+}
+
+// The vtable is only ever built for the type the frame was written with.
+fn ip<F: Frame + Copy>(frame: &CustomFrame) -> usize {
+    unsafe { frame.get::<F>() }.ip()
+}
+
+fn symbol_address<F: Frame + Copy>(frame: &CustomFrame) -> *mut c_void {
+    unsafe { frame.get::<F>() }.symbol_address()
+}
+
+fn resolve_symbol<F: Frame + Copy>(frame: &CustomFrame, cb: &mut dyn FnMut(&Symbol)) {
+    unsafe { frame.get::<F>() }.resolve_symbol(|symbol| cb(&Symbol::from(symbol)))
+}
+
+fn method<F: Frame + Copy>(frame: &CustomFrame) -> Option<UnwindMethod> {
+    unsafe { frame.get::<F>() }.method()
+}
+
+impl Debug for CustomFrame {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("CustomFrame")
+            .field("ip", &self.ip())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug)]
+    struct GreenFrame {
+        ip: usize,
+        depth: u32,
+    }
+
+    struct GreenSymbol(u32);
+
+    impl super::super::Symbol for GreenSymbol {
+        fn name(&self) -> Option<Vec<u8>> {
+            Some(format!("green_{}", self.0).into_bytes())
+        }
+
+        fn addr(&self) -> Option<*mut c_void> {
+            None
+        }
+
+        fn lineno(&self) -> Option<u32> {
+            None
+        }
+
+        fn filename(&self) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    impl Frame for GreenFrame {
+        type S = GreenSymbol;
+
+        fn resolve_symbol<F: FnMut(&Self::S)>(&self, mut cb: F) {
+            cb(&GreenSymbol(self.depth))
+        }
+
+        fn symbol_address(&self) -> *mut c_void {
+            self.ip as *mut c_void
+        }
+
+        fn ip(&self) -> usize {
+            self.ip
+        }
+
+        fn method(&self) -> Option<UnwindMethod> {
+            Some(UnwindMethod::FramePointer)
+        }
+    }
+
+    /// The same frames as `GreenFrame`, found with the unwind information.
+    #[derive(Clone, Copy, Debug)]
+    struct CfiFrame(GreenFrame);
+
+    impl Frame for CfiFrame {
+        type S = GreenSymbol;
+
+        fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F) {
+            self.0.resolve_symbol(cb)
+        }
+
+        fn symbol_address(&self) -> *mut c_void {
+            self.0.symbol_address()
+        }
+
+        fn ip(&self) -> usize {
+            self.0.ip()
+        }
+
+        fn method(&self) -> Option<UnwindMethod> {
+            Some(UnwindMethod::UnwindInfo)
+        }
+    }
+
+    struct GreenTrace;
+
+    impl Trace for GreenTrace {
+        type Frame = GreenFrame;
+
+        fn trace<F: FnMut(&Self::Frame) -> bool>(_: *mut c_void, mut cb: F) {
+            for depth in 0..3 {
+                let frame = GreenFrame {
+                    ip: 0x1000 * (depth as usize + 1),
+                    depth,
+                };
+                if !cb(&frame) {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn custom_frames() {
+        let unwinder = CustomUnwinder::of::<GreenTrace>();
+        assert!((unwinder.name)().ends_with("GreenTrace"));
+
+        let mut frames = Vec::new();
+        (unwinder.trace)(std::ptr::null_mut(), &mut |frame| {
+            frames.push(frame.clone());
+            frames.len() < 2
+        });
+        assert_eq!(frames.len(), 2);
+
+        let frame = &frames[1];
+        assert_eq!(frame.ip(), 0x2000);
+        assert_eq!(frame.symbol_address(), 0x2000 as *mut c_void);
+        assert_eq!(frame.method(), Some(UnwindMethod::FramePointer));
+
+        let mut names = Vec::new();
+        frame.resolve_symbol(|symbol| names.push(symbol.name()));
+        assert_eq!(names, vec!["green_1"]);
+    }
+
+    #[test]
+    fn keys_of_methods() {
+        use crate::backtrace::FrameImpl;
+        use crate::frames::UnresolvedFrames;
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        use std::time::SystemTime;
+
+        let frame = GreenFrame {
+            ip: 0x1000,
+            depth: 0,
+        };
+        let key = |frame: CustomFrame| {
+            UnresolvedFrames::new(
+                vec![FrameImpl::Custom(frame)],
+                b"test",
+                0,
+                SystemTime::now(),
+            )
+        };
+        let hash = |key: &UnresolvedFrames| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // the same stack found by two methods is counted separately
+        let fp = key(CustomFrame::new(frame));
+        let cfi = key(CustomFrame::new(CfiFrame(frame)));
+        assert!(fp != cfi);
+        assert_ne!(hash(&fp), hash(&cfi));
+
+        let other = key(CustomFrame::new(frame));
+        assert!(fp == other);
+        assert_eq!(hash(&fp), hash(&other));
+    }
+}
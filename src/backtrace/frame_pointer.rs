@@ -1,14 +1,31 @@
 // Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::mem::size_of;
 use std::ptr::null_mut;
 
 use libc::c_void;
 
-use crate::addr_validate::validate;
+use super::Stack;
 
 #[derive(Clone, Debug)]
 pub struct Frame {
     pub ip: usize,
+    /// Whether `ip` is the address of the interrupted instruction, rather than
+    /// a return address which follows the call.
+    pub exact: bool,
+}
+
+impl Frame {
+    /// Returns the address to resolve the frame with. The symbolizers look up
+    /// the address before the given one, which is the call for a return
+    /// address, so the one of the interrupted instruction is shifted.
+    fn lookup_address(&self) -> *mut c_void {
+        if self.exact {
+            self.ip.wrapping_add(1) as *mut c_void
+        } else {
+            self.ip as *mut c_void
+        }
+    }
 }
 
 extern "C" {
@@ -24,14 +41,14 @@ impl super::Frame for Frame {
     }
 
     fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F) {
-        backtrace::resolve(self.ip as *mut c_void, cb);
+        backtrace::resolve(self.lookup_address(), cb);
     }
 
     fn symbol_address(&self) -> *mut libc::c_void {
         if cfg!(target_os = "macos") || cfg!(target_os = "ios") {
             self.ip as *mut c_void
         } else {
-            unsafe { _Unwind_FindEnclosingFunction(self.ip as *mut c_void) }
+            unsafe { _Unwind_FindEnclosingFunction(self.lookup_address()) }
         }
     }
 }
@@ -50,10 +67,99 @@ unsafe fn read_ptr<T>(ptr: *const T) -> T {
     }
 }
 
+/// The largest distance between the entry of a frameless leaf function and the
+/// interrupted instruction in it, when its caller is recovered.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const MAX_LEAF_SIZE: usize = 4096;
+
+/// The size of the call instructions which `call_target` decodes.
+#[cfg(target_arch = "x86_64")]
+const CALL_SIZE: usize = 6;
+#[cfg(target_arch = "aarch64")]
+const CALL_SIZE: usize = 4;
+
+/// Returns the target of the call which precedes the return address `ret`, if
+/// it's known without the registers of the caller. The `CALL_SIZE` bytes
+/// before `ret` must be readable.
+#[cfg(target_arch = "x86_64")]
+fn call_target(ret: usize) -> Option<usize> {
+    let call = ret.wrapping_sub(CALL_SIZE);
+    let offset = unsafe { read_ptr((ret - 4) as *const i32) };
+
+    // call rel32: e8, then the offset from the next instruction
+    if unsafe { read_ptr((ret - 5) as *const u8) } == 0xe8 {
+        return Some(ret.wrapping_add(offset as isize as usize));
+    }
+
+    // call *rel32(%rip): ff 15, then the offset of the pointer to the target,
+    // e.g. in the global offset table
+    if unsafe { read_ptr(call as *const [u8; 2]) } == [0xff, 0x15] {
+        let slot = ret.wrapping_add(offset as isize as usize);
+        if crate::modules::is_loaded(slot, size_of::<usize>()) {
+            return Some(unsafe { read_ptr(slot as *const usize) });
+        }
+    }
+
+    None
+}
+
+/// Returns the target of the direct call which precedes the return address
+/// `ret`, if there is one. The `CALL_SIZE` bytes before `ret` must be
+/// readable.
+#[cfg(target_arch = "aarch64")]
+fn call_target(ret: usize) -> Option<usize> {
+    // bl imm26: the offset is in instructions, from the call
+    let call = ret.wrapping_sub(CALL_SIZE);
+    if call % 4 != 0 {
+        return None;
+    }
+    let instruction = unsafe { read_ptr(call as *const u32) };
+    if instruction & 0xfc00_0000 != 0x9400_0000 {
+        return None;
+    }
+    let offset = ((instruction << 6) as i32 >> 6) as isize * 4;
+    Some(call.wrapping_add(offset as usize))
+}
+
+/// Tells whether `ret`, the link register or the top of the stack, is the
+/// return address into the caller of a frameless leaf function which contains
+/// `pc`. It's a heuristic: the callers of the leaf functions called through a
+/// register or the PLT are not recovered.
+///
+/// The instructions are only read in the executable segments of the loaded
+/// modules, which are looked up without a syscall, unlike `validate`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn is_leaf_return_address(ret: usize, pc: usize) -> bool {
+    if !crate::modules::is_code(ret.wrapping_sub(CALL_SIZE), CALL_SIZE) {
+        return false;
+    }
+    match call_target(ret) {
+        Some(target) => is_leaf_call(ret, target, pc),
+        None => false,
+    }
+}
+
+/// Tells whether the call which returns to `ret` is a call to a leaf function
+/// at `target` which contains `pc`: the leaf starts at most `MAX_LEAF_SIZE`
+/// before `pc`, and the call is from outside of it.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn is_leaf_call(ret: usize, target: usize, pc: usize) -> bool {
+    let call = ret.wrapping_sub(1);
+    target <= pc && pc - target < MAX_LEAF_SIZE && !(target <= call && call <= pc)
+}
+
 pub struct Trace {}
 impl super::Trace for Trace {
     type Frame = Frame;
 
+    fn init() {
+        crate::modules::refresh_segments();
+    }
+
+    fn refresh() {
+        crate::modules::refresh_segments();
+    }
+
     fn trace<F: FnMut(&Self::Frame) -> bool>(ucontext: *mut libc::c_void, mut cb: F) {
         let ucontext: *mut libc::ucontext_t = ucontext as *mut libc::ucontext_t;
         if ucontext.is_null() {
@@ -93,25 +199,127 @@ impl super::Trace for Trace {
         #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
         let frame_pointer = unsafe { (*ucontext).uc_mcontext.__gregs[22] as usize };
 
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        let (pc, stack_pointer) = unsafe {
+            let gregs = &(*ucontext).uc_mcontext.gregs;
+            (
+                gregs[libc::REG_RIP as usize] as usize,
+                gregs[libc::REG_RSP as usize] as usize,
+            )
+        };
+
+        #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
+        let (pc, stack_pointer) = unsafe {
+            let mcontext = (*ucontext).uc_mcontext;
+            if mcontext.is_null() {
+                (0, 0)
+            } else {
+                (
+                    (*mcontext).__ss.__rip as usize,
+                    (*mcontext).__ss.__rsp as usize,
+                )
+            }
+        };
+
+        #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+        let (pc, stack_pointer, link_register) = unsafe {
+            let mcontext = &(*ucontext).uc_mcontext;
+            (
+                mcontext.pc as usize,
+                mcontext.sp as usize,
+                mcontext.regs[30] as usize,
+            )
+        };
+
+        #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+        let (pc, stack_pointer, link_register) = unsafe {
+            let mcontext = (*ucontext).uc_mcontext;
+            if mcontext.is_null() {
+                (0, 0, 0)
+            } else {
+                (
+                    (*mcontext).__ss.__pc as usize,
+                    (*mcontext).__ss.__sp as usize,
+                    (*mcontext).__ss.__lr as usize,
+                )
+            }
+        };
+
+        #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+        let (pc, stack_pointer) = unsafe {
+            let gregs = &(*ucontext).uc_mcontext.__gregs;
+            (gregs[libc::REG_PC] as usize, gregs[libc::REG_SP] as usize)
+        };
+
+        #[cfg(all(target_arch = "loongarch64", target_os = "linux"))]
+        let (pc, stack_pointer) = unsafe {
+            let mcontext = &(*ucontext).uc_mcontext;
+            (mcontext.__pc as usize, mcontext.__gregs[3] as usize)
+        };
+
+        // the reads of the frames are checked against the bounds of the stack
+        // of the thread, if they're known
+        let stack = Stack::current(stack_pointer);
+
+        // The interrupted function is the leaf, which the frame pointers only
+        // give the callers of.
+        if pc != 0
+            && !cb(&Frame {
+                ip: pc,
+                exact: true,
+            })
+        {
+            return;
+        }
+
+        // A leaf function which doesn't set up a frame isn't in the chain of
+        // the frame pointers, which continues with the caller of its caller.
+        // The return address into its caller is still in the link register,
+        // or on top of the stack until it pushes anything.
+        #[cfg(target_arch = "x86_64")]
+        let leaf_return_address =
+            if stack_pointer != 0 && stack.is_readable(stack_pointer, size_of::<usize>()) {
+                unsafe { read_ptr(stack_pointer as *const usize) }
+            } else {
+                0
+            };
+        #[cfg(target_arch = "aarch64")]
+        let leaf_return_address = link_register;
+
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if is_leaf_return_address(leaf_return_address, pc) {
+            // the leaf may have set up a frame after all, whose return
+            // address is the first one in the chain
+            let first_return_address = if stack.is_readable(frame_pointer, LAYOUT_SIZE) {
+                unsafe { read_ptr(frame_pointer as *const FramePointerLayout).ret }
+            } else {
+                0
+            };
+            if leaf_return_address != first_return_address
+                && !cb(&Frame {
+                    ip: leaf_return_address,
+                    exact: false,
+                })
+            {
+                return;
+            }
+        }
+
         let mut frame_pointer = frame_pointer as *mut FramePointerLayout;
 
         let mut last_frame_pointer: *mut FramePointerLayout = null_mut();
         loop {
-            // The stack grow from high address to low address.
-            // but we don't have a reasonable assumption for the hightest address
-            // the `__libc_stack_end` is not thread-local, and only represent the
-            // stack end of the main thread. For other thread, their stacks are allocated
-            // by the `pthread`.
-            //
-            // TODO: If we can hook the thread creation, we will have chance to get the
-            // stack end through `pthread_get_attr`.
+            // The stack grow from high address to low address. The bounds of
+            // the stack are known for the threads which were registered with
+            // `register_thread` or resolved after their first sample, and the
+            // frame pointers of the other ones are checked with `validate`.
 
             // the frame pointer should never be smaller than the former one.
             if !last_frame_pointer.is_null() && frame_pointer < last_frame_pointer {
                 break;
             }
 
-            if !validate(frame_pointer as *const libc::c_void) {
+            if !stack.is_readable(frame_pointer as usize, LAYOUT_SIZE) {
                 break;
             }
             last_frame_pointer = frame_pointer;
@@ -119,6 +327,7 @@ impl super::Trace for Trace {
             // iterate to the next frame
             let frame = Frame {
                 ip: unsafe { read_ptr(frame_pointer).ret },
+                exact: false,
             };
 
             if !cb(&frame) {
@@ -135,6 +344,20 @@ struct FramePointerLayout {
     ret: usize,
 }
 
+const LAYOUT_SIZE: usize = size_of::<FramePointerLayout>();
+
+/// Reads the frame record at `frame_pointer`, which holds the frame pointer
+/// and the return address of the caller, if it's on the stack.
+#[allow(dead_code)]
+pub(super) fn read_frame(stack: &Stack, frame_pointer: usize) -> Option<(usize, usize)> {
+    if frame_pointer == 0 || !stack.is_readable(frame_pointer, LAYOUT_SIZE) {
+        return None;
+    }
+
+    let frame = unsafe { read_ptr(frame_pointer as *const FramePointerLayout) };
+    Some((frame.frame_pointer as usize, frame.ret))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -163,4 +386,35 @@ mod tests {
         let ptr: *const usize = unsafe { std::mem::transmute(&x.0[1] as *const u8) };
         assert_eq!(unsafe { read_ptr(ptr) }, expected);
     }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_leaf_return_address() {
+        // a call at 0x10 to a leaf function at 0x100
+        let mut code = vec![0x90u8; 0x200];
+        code[0x10] = 0xe8;
+        code[0x11..0x15].copy_from_slice(&(0x100i32 - 0x15).to_ne_bytes());
+        let base = code.as_ptr() as usize;
+        let ret = base + 0x15;
+
+        assert_eq!(call_target(ret), Some(base + 0x100));
+        assert!(is_leaf_call(ret, base + 0x100, base + 0x120));
+        // the interrupted instruction is not in the called function
+        assert!(!is_leaf_call(ret, base + 0x100, base + 0x20));
+        assert!(!is_leaf_call(
+            ret,
+            base + 0x100,
+            base + 0x100 + MAX_LEAF_SIZE
+        ));
+        // there is no call before it
+        assert_eq!(call_target(ret + 1), None);
+        // the instructions are only read in the code of the modules
+        assert!(!is_leaf_return_address(ret, base + 0x120));
+
+        let frame = Frame {
+            ip: base + 0x120,
+            exact: true,
+        };
+        assert_eq!(frame.lookup_address() as usize, base + 0x121);
+    }
 }
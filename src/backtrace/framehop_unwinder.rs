@@ -1,13 +1,16 @@
 use framehop::{
-    CacheNative, MustNotAllocateDuringUnwind, UnwindRegsNative, Unwinder, UnwinderNative,
+    CacheNative, Module, MustNotAllocateDuringUnwind, UnwindRegsNative, Unwinder, UnwinderNative,
 };
 use libc::{c_void, ucontext_t};
 use once_cell::sync::Lazy;
 use spin::RwLock;
 mod shlib;
 
+use super::Stack;
+use shlib::ModuleKey;
+
 #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
-fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
+pub(super) fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
     let ucontext: *mut ucontext_t = ucontext as *mut ucontext_t;
     if ucontext.is_null() {
         return None;
@@ -29,7 +32,7 @@ fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64
 }
 
 #[cfg(all(target_arch = "x86_64", target_os = "macos"))]
-fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
+pub(super) fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
     let ucontext: *mut ucontext_t = ucontext as *mut ucontext_t;
     if ucontext.is_null() {
         return None;
@@ -51,7 +54,7 @@ fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64
 }
 
 #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
-fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
+pub(super) fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
     let ucontext: *mut ucontext_t = ucontext as *mut ucontext_t;
     if ucontext.is_null() {
         return None;
@@ -63,7 +66,7 @@ fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64
 }
 
 #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
-fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
+pub(super) fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64)> {
     let ucontext: *mut ucontext_t = ucontext as *mut ucontext_t;
     if ucontext.is_null() {
         return None;
@@ -80,19 +83,45 @@ fn get_regs_from_context(ucontext: *mut c_void) -> Option<(UnwindRegsNative, u64
     ))
 }
 
-struct FramehopUnwinder {
-    unwinder: UnwinderNative<Vec<u8>, MustNotAllocateDuringUnwind>,
-    cache: CacheNative<MustNotAllocateDuringUnwind>,
+pub(super) struct FramehopUnwinder {
+    pub(super) unwinder: UnwinderNative<Vec<u8>, MustNotAllocateDuringUnwind>,
+    pub(super) cache: CacheNative<MustNotAllocateDuringUnwind>,
+    // the libraries whose modules were added to the unwinder
+    modules: Vec<ModuleKey>,
 }
 
 impl FramehopUnwinder {
     pub fn new() -> Self {
         let mut unwinder = UnwinderNative::new();
-        for obj in shlib::get_objects() {
-            unwinder.add_module(obj.clone());
+        let (objects, modules) = shlib::find_objects(&[]);
+        for obj in objects {
+            unwinder.add_module(obj);
         }
         let cache = CacheNative::default();
-        FramehopUnwinder { unwinder, cache }
+        FramehopUnwinder {
+            unwinder,
+            cache,
+            modules,
+        }
+    }
+
+    /// Adds the modules of the libraries loaded since the last update, and
+    /// removes the ones of the libraries which were unloaded.
+    fn update(&mut self, objects: Vec<Module<Vec<u8>>>, modules: Vec<ModuleKey>) {
+        for key in &self.modules {
+            if !modules.contains(key) {
+                self.unwinder.remove_module(key.0);
+            }
+        }
+        for obj in objects {
+            // it may have been added by a concurrent update
+            let key = (obj.base_avma(), obj.name().to_owned());
+            if !self.modules.contains(&key) {
+                self.unwinder.add_module(obj);
+            }
+        }
+        // the unwinder invalidates the rules cached for the previous modules
+        self.modules = modules;
     }
 
     pub fn iter_frames<F: FnMut(&Frame) -> bool>(&mut self, ctx: *mut c_void, mut cb: F) {
@@ -101,7 +130,8 @@ impl FramehopUnwinder {
             None => return,
         };
 
-        let mut closure = |addr| read_stack(addr);
+        let stack = Stack::current(regs.sp() as usize);
+        let mut closure = |addr| read_stack(&stack, addr);
         let mut iter = self
             .unwinder
             .iter_frames(pc, regs, &mut self.cache, &mut closure);
@@ -115,9 +145,9 @@ impl FramehopUnwinder {
     }
 }
 
-fn read_stack(addr: u64) -> Result<u64, ()> {
+pub(super) fn read_stack(stack: &Stack, addr: u64) -> Result<u64, ()> {
     let aligned_addr = addr & !0b111;
-    if crate::addr_validate::validate(aligned_addr as _) {
+    if stack.is_readable(aligned_addr as usize, 8) {
         Ok(unsafe { (aligned_addr as *const u64).read() })
     } else {
         Err(())
@@ -126,6 +156,15 @@ fn read_stack(addr: u64) -> Result<u64, ()> {
 
 static UNWINDER: Lazy<RwLock<FramehopUnwinder>> =
     Lazy::new(|| RwLock::new(FramehopUnwinder::new()));
+
+/// Runs `f` with the unwinder, unless it's being updated. It's called in the
+/// signal handler.
+pub(super) fn with_unwinder<R>(f: impl FnOnce(&mut FramehopUnwinder) -> R) -> Option<R> {
+    // For Linux, this `try_write` should always succeed, because `SIGPROF` will never be delivered to
+    // another thread while the signal handler is running. However, I'm not sure about other OSes, so
+    // we use `try_write` to be safe instead of using `static mut` and `unsafe` directly.
+    UNWINDER.try_write().map(|mut unwinder| f(&mut unwinder))
+}
 #[derive(Clone, Debug)]
 pub struct Frame {
     pub ip: usize,
@@ -164,18 +203,18 @@ impl super::Trace for Trace {
         let _ = UNWINDER.read();
     }
 
+    fn refresh() {
+        // The objects are loaded before taking the lock, which is only held
+        // to update the unwinder. The signal handler doesn't wait for it.
+        let known = UNWINDER.read().modules.clone();
+        let (objects, modules) = shlib::find_objects(&known);
+        UNWINDER.write().update(objects, modules);
+    }
+
     fn trace<F: FnMut(&Self::Frame) -> bool>(ctx: *mut c_void, cb: F)
     where
         Self: Sized,
     {
-        // For Linux, this `try_write` should always succeed, because `SIGPROF` will never be delivered to
-        // another thread while the signal handler is running. However, I'm not sure about other OSes, so
-        // we use `try_write` to be safe instead of using `static mut` and `unsafe` directly.
-        match UNWINDER.try_write() {
-            None => return,
-            Some(mut unwinder) => {
-                unwinder.iter_frames(ctx, cb);
-            }
-        }
+        with_unwinder(|unwinder| unwinder.iter_frames(ctx, cb));
     }
 }
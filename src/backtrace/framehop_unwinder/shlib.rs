@@ -4,13 +4,9 @@ use findshlibs::{SharedLibrary, TargetSharedLibrary};
 use framehop::{Module, ModuleSectionInfo};
 use memmap2::Mmap;
 use object::{Object, ObjectSection};
-use once_cell::sync::Lazy;
 
-static OBJECTS: Lazy<Vec<Module<Vec<u8>>>> = Lazy::new(find_objects);
-
-pub fn get_objects() -> &'static [Module<Vec<u8>>] {
-    &OBJECTS
-}
+/// The start address and the name of a loaded library.
+pub type ModuleKey = (u64, String);
 
 pub struct ObjectInfo<'f>(object::File<'f>);
 
@@ -53,26 +49,35 @@ fn open_mmap(path: &PathBuf) -> Option<Mmap> {
     Some(mmap)
 }
 
-fn find_objects() -> Vec<Module<Vec<u8>>> {
+/// Loads the objects of the libraries which are not in `known`, and returns
+/// them with the keys of all the libraries loaded now.
+pub fn find_objects(known: &[ModuleKey]) -> (Vec<Module<Vec<u8>>>, Vec<ModuleKey>) {
     let mut objects = Vec::new();
+    let mut loaded = Vec::new();
     // objects
     TargetSharedLibrary::each(|shlib| {
         let path = PathBuf::from(shlib.name());
         let base_avma = shlib.actual_load_addr().0 as u64;
         let avma_range = base_avma..base_avma + shlib.len() as u64;
+        let key = (base_avma, path.to_string_lossy().to_string());
+        if known.contains(&key) {
+            loaded.push(key);
+            return;
+        }
         if let Some(mmap) = open_mmap(&path) {
             if let Ok(obj) = object::File::parse(&*mmap) {
                 let section_info = ObjectInfo(obj);
 
                 objects.push(Module::new(
-                    path.to_string_lossy().to_string(),
+                    key.1.clone(),
                     avma_range,
                     base_avma,
                     section_info,
                 ));
+                loaded.push(key);
             }
         }
     });
 
-    objects
+    (objects, loaded)
 }
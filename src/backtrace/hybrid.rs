@@ -0,0 +1,213 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use framehop::{FrameAddress, UnwindRegsNative, Unwinder as _};
+use libc::c_void;
+
+use super::framehop_unwinder::{self, get_regs_from_context, read_stack};
+use super::{frame_pointer, Stack, UnwindMethod};
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub ip: usize,
+    pub method: UnwindMethod,
+}
+
+impl Frame {
+    /// Returns the address to resolve the frame with. The symbolizers look up
+    /// the address before the given one, which is the call for a return
+    /// address, so the one of the interrupted instruction is shifted.
+    fn lookup_address(&self) -> *mut c_void {
+        if self.method == UnwindMethod::Context {
+            self.ip.wrapping_add(1) as *mut c_void
+        } else {
+            self.ip as *mut c_void
+        }
+    }
+}
+
+extern "C" {
+    fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut c_void;
+}
+
+impl super::Frame for Frame {
+    type S = backtrace::Symbol;
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F) {
+        backtrace::resolve(self.lookup_address(), cb);
+    }
+
+    fn symbol_address(&self) -> *mut c_void {
+        if cfg!(target_os = "macos") || cfg!(target_os = "ios") {
+            self.ip as *mut c_void
+        } else {
+            unsafe { _Unwind_FindEnclosingFunction(self.lookup_address()) }
+        }
+    }
+
+    fn method(&self) -> Option<UnwindMethod> {
+        Some(self.method)
+    }
+}
+
+/// Unwinds every frame with the unwind information of `framehop`, and falls
+/// back to the frame pointer for the frames it fails on, e.g. in a library
+/// without CFI. The next frame is tried with the unwind information again, so
+/// that a function without a frame pointer doesn't end the stack either.
+pub struct Trace;
+
+impl super::Trace for Trace {
+    type Frame = Frame;
+
+    fn init() {
+        framehop_unwinder::Trace::init();
+    }
+
+    fn refresh() {
+        framehop_unwinder::Trace::refresh();
+    }
+
+    fn trace<F: FnMut(&Self::Frame) -> bool>(ucontext: *mut c_void, mut cb: F) {
+        let (mut regs, pc) = match get_regs_from_context(ucontext) {
+            Some(context) => context,
+            None => return,
+        };
+
+        let stack = Stack::current(regs.sp() as usize);
+        let mut read = |addr| read_stack(&stack, addr);
+
+        framehop_unwinder::with_unwinder(|unwinder| {
+            if !cb(&Frame {
+                ip: pc as usize,
+                method: UnwindMethod::Context,
+            }) {
+                return;
+            }
+
+            let mut address = FrameAddress::from_instruction_pointer(pc);
+            loop {
+                let mut caller_regs = regs;
+                let (ret, method) = match unwinder.unwinder.unwind_frame(
+                    address,
+                    &mut caller_regs,
+                    &mut unwinder.cache,
+                    &mut read,
+                ) {
+                    Ok(Some(ret)) => (ret, UnwindMethod::UnwindInfo),
+                    // the unwind information marks the root of the stack
+                    Ok(None) => break,
+                    Err(_) => {
+                        caller_regs = regs;
+                        match unwind_frame_pointer(&stack, &mut caller_regs) {
+                            Some(ret) => (ret, UnwindMethod::FramePointer),
+                            None => break,
+                        }
+                    }
+                };
+
+                address = match FrameAddress::from_return_address(ret) {
+                    Some(address) => address,
+                    None => break,
+                };
+                regs = caller_regs;
+                if !cb(&Frame {
+                    ip: ret as usize,
+                    method,
+                }) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Restores the registers of the caller from the frame record which the frame
+/// pointer points to, and returns the return address. The record has to be
+/// above the stack pointer, so that the unwinding always moves up the stack.
+#[cfg(target_arch = "x86_64")]
+fn unwind_frame_pointer(stack: &Stack, regs: &mut UnwindRegsNative) -> Option<u64> {
+    let frame_pointer = regs.bp();
+    if frame_pointer < regs.sp() {
+        return None;
+    }
+    let (caller_frame_pointer, ret) = frame_pointer::read_frame(stack, frame_pointer as usize)?;
+
+    regs.set_bp(caller_frame_pointer as u64);
+    regs.set_sp(frame_pointer + 16);
+    regs.set_ip(ret as u64);
+    Some(ret as u64)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn unwind_frame_pointer(stack: &Stack, regs: &mut UnwindRegsNative) -> Option<u64> {
+    let frame_pointer = regs.fp();
+    if frame_pointer < regs.sp() {
+        return None;
+    }
+    let (caller_frame_pointer, ret) = frame_pointer::read_frame(stack, frame_pointer as usize)?;
+    let ret = regs.lr_mask().strip_ptr_auth(ret as u64);
+
+    regs.set_fp(caller_frame_pointer as u64);
+    regs.set_sp(frame_pointer + 16);
+    regs.set_lr(ret);
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backtrace::Trace as _;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn unwind_with_frame_pointer() {
+        // a frame record, with the frame pointer and the return address of the caller
+        let record: [usize; 2] = [0x1234, 0x5678];
+        let frame_pointer = record.as_ptr() as u64;
+        let stack = Stack::current(frame_pointer as usize - 64);
+
+        let mut regs = UnwindRegsNative::new(0, frame_pointer - 64, frame_pointer);
+        assert_eq!(unwind_frame_pointer(&stack, &mut regs), Some(0x5678));
+        assert_eq!(regs.bp(), 0x1234);
+        assert_eq!(regs.sp(), frame_pointer + 16);
+        assert_eq!(regs.ip(), 0x5678);
+
+        // the record is below the stack pointer
+        let mut regs = UnwindRegsNative::new(0, frame_pointer + 64, frame_pointer);
+        assert_eq!(unwind_frame_pointer(&stack, &mut regs), None);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn unwind_from_context() {
+        let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::getcontext(&mut context) }, 0);
+
+        Trace::init();
+        let mut frames = Vec::new();
+        Trace::trace(
+            &mut context as *mut libc::ucontext_t as *mut c_void,
+            |frame| {
+                frames.push(frame.clone());
+                true
+            },
+        );
+
+        assert!(frames.len() > 2);
+        assert_eq!(frames[0].method, UnwindMethod::Context);
+        assert!(frames[1..]
+            .iter()
+            .all(|frame| frame.method != UnwindMethod::Context));
+
+        let mut name = String::new();
+        super::super::Frame::resolve_symbol(&frames[0], |symbol| {
+            if let Some(symbol_name) = symbol.name() {
+                name = symbol_name.to_string();
+            }
+        });
+        assert!(name.contains("unwind_from_context"), "{}", name);
+    }
+}
@@ -2,7 +2,10 @@
 
 use libc::c_void;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicPtr, AtomicU8, Ordering};
 
+/// A symbol which an address of a [`Frame`] resolves to. There may be several
+/// of them for an address, e.g. when functions were inlined.
 pub trait Symbol: Sized {
     fn name(&self) -> Option<Vec<u8>>;
     fn addr(&self) -> Option<*mut c_void>;
@@ -28,49 +31,71 @@ impl Symbol for backtrace::Symbol {
     }
 }
 
+/// A frame found by a [`Trace`].
+///
+/// `ip` and `symbol_address` are called in the signal handler, when the sample
+/// is recorded, and must be async-signal-safe as `Trace::trace` is.
+/// `resolve_symbol` is only called when a report is built.
 pub trait Frame: Sized + Clone {
     type S: Symbol;
 
+    /// Calls `cb` with every symbol of the frame, the innermost first.
     fn resolve_symbol<F: FnMut(&Self::S)>(&self, cb: F);
 
-    #[allow(dead_code)]
+    /// Returns the address of the function of the frame. The samples whose
+    /// frames have the same ones are merged.
     fn symbol_address(&self) -> *mut c_void;
 
-    #[allow(dead_code)]
+    /// Returns the instruction pointer of the frame: the interrupted
+    /// instruction for the first frame, and a return address for the others.
     fn ip(&self) -> usize;
+
+    /// Returns how the frame was found, if the unwinder tells it.
+    fn method(&self) -> Option<UnwindMethod> {
+        None
+    }
 }
 
+/// An unwinder, which walks the stack of the interrupted thread. A custom one
+/// can be given to `ProfilerGuardBuilder::custom_unwinder`, e.g. to walk the
+/// stacks of green threads.
+///
+/// `trace` runs in the signal handler, so it must be async-signal-safe: it
+/// must not allocate or free memory, wait for a lock, panic, or call any
+/// function which isn't async-signal-safe. The memory it reads should be
+/// checked first, e.g. with [`validate`](crate::validate), as a fault in the
+/// handler kills the process. `init` and `refresh` run outside of it, and can
+/// prepare whatever `trace` needs.
 pub trait Trace {
-    type Frame;
+    type Frame: Frame;
 
-    // init will be called before running the first trace in signal handler
+    /// Called before the first trace in the signal handler.
     fn init() {}
 
-    fn trace<F: FnMut(&Self::Frame) -> bool>(_: *mut libc::c_void, cb: F)
+    /// Called outside of the signal handler, by `ProfilerGuard::refresh_modules`,
+    /// when libraries were loaded or unloaded since `init`.
+    fn refresh() {}
+
+    /// Calls `cb` with every frame of the stack, from the leaf, until it returns
+    /// `false`. `ucontext` is the `ucontext_t` given to the signal handler,
+    /// which holds the registers of the interrupted thread. It's the one of
+    /// `getcontext` for the captures outside of the handler, e.g. by the heap
+    /// profiler, and may be null where it's not available.
+    fn trace<F: FnMut(&Self::Frame) -> bool>(ucontext: *mut libc::c_void, cb: F)
     where
         Self: Sized;
 }
 
-#[cfg(not(all(
-    any(
-        target_arch = "x86_64",
-        target_arch = "aarch64",
-        target_arch = "riscv64",
-        target_arch = "loongarch64"
-    ),
-    any(feature = "frame-pointer", feature = "framehop-unwinder")
-)))]
+mod stack;
+pub use stack::register_thread;
+pub(crate) use stack::resolve_stacks;
+#[allow(unused_imports)]
+pub(crate) use stack::Stack;
+
 mod backtrace_rs;
-#[cfg(not(all(
-    any(
-        target_arch = "x86_64",
-        target_arch = "aarch64",
-        target_arch = "riscv64",
-        target_arch = "loongarch64"
-    ),
-    any(feature = "frame-pointer", feature = "framehop-unwinder")
-)))]
-pub use backtrace_rs::Trace as TraceImpl;
+mod custom;
+pub(crate) use custom::CustomUnwinder;
+pub use custom::CUSTOM_FRAME_WORDS;
 
 #[cfg(all(
     any(
@@ -82,16 +107,6 @@ pub use backtrace_rs::Trace as TraceImpl;
     feature = "frame-pointer"
 ))]
 pub mod frame_pointer;
-#[cfg(all(
-    any(
-        target_arch = "x86_64",
-        target_arch = "aarch64",
-        target_arch = "riscv64",
-        target_arch = "loongarch64"
-    ),
-    feature = "frame-pointer"
-))]
-pub use frame_pointer::Trace as TraceImpl;
 
 #[cfg(all(
     any(target_arch = "x86_64", target_arch = "aarch64",),
@@ -103,6 +118,513 @@ pub mod framehop_unwinder;
 #[cfg(all(
     any(target_arch = "x86_64", target_arch = "aarch64",),
     any(target_os = "linux", target_os = "macos",),
-    feature = "framehop-unwinder"
+    feature = "framehop-unwinder",
+    feature = "frame-pointer"
 ))]
-pub use framehop_unwinder::Trace as TraceImpl;
+pub mod hybrid;
+
+/// The key of the label which tells how every frame of a sample was found by
+/// the `Hybrid` unwinder, with one letter per frame from the leaf: `c` for
+/// the interrupted instruction given by the signal context, `u` for a frame
+/// found with the unwind information, `f` for one found with the frame
+/// pointer, and `v` for a virtual frame of [`shadow`](crate::shadow). E.g.
+/// `cuuffu`.
+pub const UNWIND_METHODS: &str = "unwind_methods";
+
+/// How a frame was found by the unwinder.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnwindMethod {
+    /// The interrupted instruction, given by the signal context.
+    Context,
+    /// The return address found with the unwind information (CFI).
+    UnwindInfo,
+    /// The return address found with the frame pointer.
+    FramePointer,
+}
+
+impl UnwindMethod {
+    /// Returns the letter of the method in the `UNWIND_METHODS` label.
+    pub fn letter(self) -> char {
+        match self {
+            UnwindMethod::Context => 'c',
+            UnwindMethod::UnwindInfo => 'u',
+            UnwindMethod::FramePointer => 'f',
+        }
+    }
+}
+
+/// The unwinder which walks the stack of the interrupted thread. The ones
+/// which are compiled in can all be selected at runtime with
+/// `ProfilerGuardBuilder::unwinder`, e.g. to compare them on a workload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unwinder {
+    /// `_Unwind_Backtrace` of libgcc, or of the libunwind of the platform,
+    /// through `backtrace-rs`. It's always available, and the default when no
+    /// other unwinder is compiled in. It unwinds the stack of the signal
+    /// handler, rather than starting from the signal context.
+    Libgcc,
+
+    /// Follows the chain of frame pointers, which needs every function to
+    /// keep one. It's the default with the `frame-pointer` feature.
+    #[cfg(all(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ),
+        feature = "frame-pointer"
+    ))]
+    FramePointer,
+
+    /// Unwinds with the unwind information of the loaded libraries, through
+    /// `framehop`. It's the default with the `framehop-unwinder` feature, even
+    /// if the `frame-pointer` one is also enabled.
+    #[cfg(all(
+        any(target_arch = "x86_64", target_arch = "aarch64",),
+        any(target_os = "linux", target_os = "macos",),
+        feature = "framehop-unwinder"
+    ))]
+    Framehop,
+
+    /// Unwinds every frame with the unwind information, as `Framehop` does,
+    /// and falls back to the frame pointer for the frames it fails on, e.g. in
+    /// a C library built without CFI. Each sample gets the
+    /// [`UNWIND_METHODS`](crate::UNWIND_METHODS) label, which tells how every
+    /// frame was found. It needs both unwinder features.
+    #[cfg(all(
+        any(target_arch = "x86_64", target_arch = "aarch64",),
+        any(target_os = "linux", target_os = "macos",),
+        feature = "framehop-unwinder",
+        feature = "frame-pointer"
+    ))]
+    Hybrid,
+}
+
+impl Default for Unwinder {
+    #[allow(unreachable_code)]
+    fn default() -> Unwinder {
+        #[cfg(all(
+            any(target_arch = "x86_64", target_arch = "aarch64",),
+            any(target_os = "linux", target_os = "macos",),
+            feature = "framehop-unwinder"
+        ))]
+        return Unwinder::Framehop;
+
+        #[cfg(all(
+            any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64",
+                target_arch = "loongarch64"
+            ),
+            feature = "frame-pointer"
+        ))]
+        return Unwinder::FramePointer;
+
+        Unwinder::Libgcc
+    }
+}
+
+static SELECTED: AtomicU8 = AtomicU8::new(u8::MAX);
+// the custom unwinder, if `SELECTED` is `CUSTOM`
+static CUSTOM_UNWINDER: AtomicPtr<CustomUnwinder> = AtomicPtr::new(std::ptr::null_mut());
+const CUSTOM: u8 = u8::MAX - 1;
+
+/// The unwinder used by `TraceImpl`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SelectedUnwinder {
+    Builtin(Unwinder),
+    Custom(&'static CustomUnwinder),
+}
+
+impl SelectedUnwinder {
+    pub fn current() -> SelectedUnwinder {
+        let selected = SELECTED.load(Ordering::Acquire);
+        if selected == CUSTOM {
+            let custom = CUSTOM_UNWINDER.load(Ordering::Acquire);
+            // it's never reset once it's set
+            return SelectedUnwinder::Custom(unsafe { &*custom });
+        }
+
+        SelectedUnwinder::Builtin(match selected {
+            n if n == Unwinder::Libgcc as u8 => Unwinder::Libgcc,
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            n if n == Unwinder::FramePointer as u8 => Unwinder::FramePointer,
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            n if n == Unwinder::Framehop as u8 => Unwinder::Framehop,
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            n if n == Unwinder::Hybrid as u8 => Unwinder::Hybrid,
+            _ => Unwinder::default(),
+        })
+    }
+
+    /// Makes it the unwinder used by `TraceImpl`. It's initialized by the
+    /// next `TraceImpl::init`.
+    pub fn select(self) {
+        match self {
+            SelectedUnwinder::Builtin(unwinder) => {
+                SELECTED.store(unwinder as u8, Ordering::Release);
+            }
+            SelectedUnwinder::Custom(custom) => {
+                CUSTOM_UNWINDER.store(
+                    custom as *const CustomUnwinder as *mut CustomUnwinder,
+                    Ordering::Release,
+                );
+                SELECTED.store(CUSTOM, Ordering::Release);
+            }
+        }
+    }
+
+    /// Returns the name of the unwinder, which is given in the errors.
+    #[allow(dead_code)]
+    pub fn name(self) -> &'static str {
+        match self {
+            SelectedUnwinder::Builtin(unwinder) => unwinder.name(),
+            SelectedUnwinder::Custom(custom) => (custom.name)(),
+        }
+    }
+
+    /// Returns whether the stack is unwound from the context given to the
+    /// signal handler, rather than from the stack on which the handler runs.
+    /// A custom unwinder is trusted to do so.
+    #[allow(dead_code)]
+    pub fn uses_context(self) -> bool {
+        match self {
+            SelectedUnwinder::Builtin(unwinder) => unwinder != Unwinder::Libgcc,
+            SelectedUnwinder::Custom(_) => true,
+        }
+    }
+}
+
+impl Unwinder {
+    /// Returns the name of the unwinder, which is given in the errors.
+    fn name(self) -> &'static str {
+        match self {
+            Unwinder::Libgcc => "libgcc",
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            Unwinder::FramePointer => "frame-pointer",
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            Unwinder::Framehop => "framehop",
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            Unwinder::Hybrid => "hybrid",
+        }
+    }
+}
+
+/// A frame given by one of the unwinders.
+#[derive(Clone, Debug)]
+pub enum FrameImpl {
+    Libgcc(backtrace::Frame),
+    #[cfg(all(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ),
+        feature = "frame-pointer"
+    ))]
+    FramePointer(frame_pointer::Frame),
+    #[cfg(all(
+        any(target_arch = "x86_64", target_arch = "aarch64",),
+        any(target_os = "linux", target_os = "macos",),
+        feature = "framehop-unwinder"
+    ))]
+    Framehop(framehop_unwinder::Frame),
+    #[cfg(all(
+        any(target_arch = "x86_64", target_arch = "aarch64",),
+        any(target_os = "linux", target_os = "macos",),
+        feature = "framehop-unwinder",
+        feature = "frame-pointer"
+    ))]
+    Hybrid(hybrid::Frame),
+    Custom(custom::CustomFrame),
+}
+
+impl Frame for FrameImpl {
+    // the symbols of the builtin unwinders are converted, so that the ones of
+    // the custom unwinders can be given too
+    type S = crate::frames::Symbol;
+
+    fn resolve_symbol<F: FnMut(&Self::S)>(&self, mut cb: F) {
+        let convert = |symbol: &backtrace::Symbol| cb(&crate::frames::Symbol::from(symbol));
+        match self {
+            FrameImpl::Libgcc(frame) => frame.resolve_symbol(convert),
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::FramePointer(frame) => frame.resolve_symbol(convert),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            FrameImpl::Framehop(frame) => frame.resolve_symbol(convert),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::Hybrid(frame) => frame.resolve_symbol(convert),
+            FrameImpl::Custom(frame) => frame.resolve_symbol(&mut cb),
+        }
+    }
+
+    fn symbol_address(&self) -> *mut c_void {
+        match self {
+            FrameImpl::Libgcc(frame) => Frame::symbol_address(frame),
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::FramePointer(frame) => frame.symbol_address(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            FrameImpl::Framehop(frame) => frame.symbol_address(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::Hybrid(frame) => frame.symbol_address(),
+            FrameImpl::Custom(frame) => frame.symbol_address(),
+        }
+    }
+
+    fn ip(&self) -> usize {
+        match self {
+            FrameImpl::Libgcc(frame) => Frame::ip(frame),
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::FramePointer(frame) => frame.ip(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            FrameImpl::Framehop(frame) => frame.ip(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::Hybrid(frame) => frame.ip(),
+            FrameImpl::Custom(frame) => frame.ip(),
+        }
+    }
+
+    fn method(&self) -> Option<UnwindMethod> {
+        match self {
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            FrameImpl::Hybrid(frame) => frame.method(),
+            FrameImpl::Custom(frame) => frame.method(),
+            _ => None,
+        }
+    }
+}
+
+/// Unwinds with the unwinder selected by `ProfilerGuardBuilder::unwinder`, or
+/// the one given to `ProfilerGuardBuilder::custom_unwinder`.
+pub struct TraceImpl;
+
+impl Trace for TraceImpl {
+    type Frame = FrameImpl;
+
+    fn init() {
+        let unwinder = match SelectedUnwinder::current() {
+            SelectedUnwinder::Builtin(unwinder) => unwinder,
+            SelectedUnwinder::Custom(custom) => return (custom.init)(),
+        };
+        match unwinder {
+            Unwinder::Libgcc => backtrace_rs::Trace::init(),
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            Unwinder::FramePointer => frame_pointer::Trace::init(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            Unwinder::Framehop => framehop_unwinder::Trace::init(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            Unwinder::Hybrid => hybrid::Trace::init(),
+        }
+    }
+
+    fn refresh() {
+        let unwinder = match SelectedUnwinder::current() {
+            SelectedUnwinder::Builtin(unwinder) => unwinder,
+            SelectedUnwinder::Custom(custom) => return (custom.refresh)(),
+        };
+        match unwinder {
+            Unwinder::Libgcc => backtrace_rs::Trace::refresh(),
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            Unwinder::FramePointer => frame_pointer::Trace::refresh(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            Unwinder::Framehop => framehop_unwinder::Trace::refresh(),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            Unwinder::Hybrid => hybrid::Trace::refresh(),
+        }
+    }
+
+    fn trace<F: FnMut(&Self::Frame) -> bool>(ucontext: *mut c_void, mut cb: F) {
+        // the frames are copied into the enum, which doesn't allocate
+        let unwinder = match SelectedUnwinder::current() {
+            SelectedUnwinder::Builtin(unwinder) => unwinder,
+            SelectedUnwinder::Custom(custom) => return (custom.trace)(ucontext, &mut cb),
+        };
+        match unwinder {
+            Unwinder::Libgcc => {
+                backtrace_rs::Trace::trace(ucontext, |frame| cb(&FrameImpl::Libgcc(frame.clone())))
+            }
+            #[cfg(all(
+                any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ),
+                feature = "frame-pointer"
+            ))]
+            Unwinder::FramePointer => frame_pointer::Trace::trace(ucontext, |frame| {
+                cb(&FrameImpl::FramePointer(frame.clone()))
+            }),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder"
+            ))]
+            Unwinder::Framehop => framehop_unwinder::Trace::trace(ucontext, |frame| {
+                cb(&FrameImpl::Framehop(frame.clone()))
+            }),
+            #[cfg(all(
+                any(target_arch = "x86_64", target_arch = "aarch64",),
+                any(target_os = "linux", target_os = "macos",),
+                feature = "framehop-unwinder",
+                feature = "frame-pointer"
+            ))]
+            Unwinder::Hybrid => {
+                hybrid::Trace::trace(ucontext, |frame| cb(&FrameImpl::Hybrid(frame.clone())))
+            }
+        }
+    }
+}
+
+/// Unwinds the stack of the current thread, outside of a signal handler. The
+/// unwinders which start from a signal context get one from `getcontext`
+/// where it's available, and see an empty stack otherwise.
+pub fn trace_current<F: FnMut(&<TraceImpl as Trace>::Frame) -> bool>(cb: F) {
+    #[allow(unused_mut)]
+    let mut ucontext: *mut c_void = std::ptr::null_mut();
+
+    #[cfg(all(
+        target_os = "linux",
+        target_env = "gnu",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+    #[cfg(all(
+        target_os = "linux",
+        target_env = "gnu",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    if unsafe { libc::getcontext(&mut context) } == 0 {
+        ucontext = &mut context as *mut libc::ucontext_t as *mut c_void;
+    }
+
+    TraceImpl::trace(ucontext, cb)
+}
@@ -0,0 +1,340 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::Cell;
+
+use crate::addr_validate::validate;
+
+thread_local! {
+    // The lowest and the highest address of the stack of the current thread,
+    // if it was registered, or resolved since it was first sampled. It has a
+    // const initializer and no destructor, so that it can be read in the
+    // signal handler.
+    static BOUNDS: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Records the bounds of the stack of the current thread. The `frame-pointer`
+/// and `framehop` unwinders check the reads of a registered thread against
+/// them, instead of asking the kernel whether every address is mapped, which
+/// costs two system calls per frame.
+///
+/// The thread which builds the profiler is registered. The other ones can be
+/// registered when they start, e.g. in `tokio::runtime::Builder::on_thread_start`
+/// through [`pprof::tokio::register_thread`](crate::tokio::register_thread) with
+/// the `tokio` feature. It's cheap to call it more than once.
+///
+/// On Linux, the bounds of a thread which isn't registered are also looked up
+/// in the memory mappings after it's first sampled, by the thread which drains
+/// the samples, so only its first samples are checked with the kernel.
+pub fn register_thread() {
+    if BOUNDS.try_with(Cell::get).ok().flatten().is_some() {
+        return;
+    }
+    if let Some(bounds) = current_bounds() {
+        let _ = BOUNDS.try_with(|cell| cell.set(Some(bounds)));
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn current_bounds() -> Option<(usize, usize)> {
+    let mut attr: libc::pthread_attr_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::pthread_getattr_np(libc::pthread_self(), &mut attr) } != 0 {
+        return None;
+    }
+
+    let mut addr: *mut libc::c_void = std::ptr::null_mut();
+    let mut size: libc::size_t = 0;
+    let ret = unsafe { libc::pthread_attr_getstack(&attr, &mut addr, &mut size) };
+    unsafe { libc::pthread_attr_destroy(&mut attr) };
+    if ret != 0 {
+        return None;
+    }
+
+    let low = addr as usize;
+    Some((low, low + size))
+}
+
+#[cfg(target_os = "macos")]
+fn current_bounds() -> Option<(usize, usize)> {
+    let thread = unsafe { libc::pthread_self() };
+    // it's the highest address, as the stack grows downwards
+    let high = unsafe { libc::pthread_get_stackaddr_np(thread) } as usize;
+    let size = unsafe { libc::pthread_get_stacksize_np(thread) };
+    Some((high - size, high))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn current_bounds() -> Option<(usize, usize)> {
+    None
+}
+
+/// The stack of the interrupted thread, which the unwinders read. It's unused
+/// by `backtrace-rs`, which unwinds the stack of the signal handler.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct Stack {
+    // the part of the stack which is in use, if the thread is registered
+    bounds: Option<(usize, usize)>,
+}
+
+#[allow(dead_code)]
+impl Stack {
+    /// Returns the stack of the current thread, whose stack pointer was
+    /// `stack_pointer` when it was interrupted. Only the addresses above it
+    /// are read: the main thread may not have mapped the lower part of its
+    /// stack yet. If it's not on its stack, e.g. on an alternate signal stack,
+    /// the stack is unknown.
+    pub fn current(stack_pointer: usize) -> Stack {
+        let bounds = match BOUNDS.try_with(Cell::get) {
+            Ok(Some(bounds)) => Some(bounds),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Ok(None) => lazy::lookup(stack_pointer),
+            _ => None,
+        };
+        let bounds = match bounds {
+            Some((low, high)) if low <= stack_pointer && stack_pointer < high => {
+                Some((stack_pointer, high))
+            }
+            _ => None,
+        };
+        Stack { bounds }
+    }
+
+    /// Tells whether `size` bytes can be read at `addr`. The reads of an
+    /// unknown stack are checked with `validate`, which covers 16 bytes.
+    pub fn is_readable(&self, addr: usize, size: usize) -> bool {
+        match self.bounds {
+            Some((low, high)) => addr >= low && addr <= high && size <= high - addr,
+            None => validate(addr as *const libc::c_void),
+        }
+    }
+}
+
+/// Resolves the bounds of the stacks of the threads which were sampled
+/// without being registered. It must not be called in the signal handler.
+pub(crate) fn resolve_stacks() {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    lazy::resolve();
+}
+
+// The bounds of the threads which were sampled without being registered. The
+// signal handler claims a slot with the id of its thread, and leaves its stack
+// pointer. `resolve` finds the mapping which contains it outside of the
+// handler, and the next sample of the thread takes its bounds from the slot
+// and releases it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod lazy {
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+    use super::BOUNDS;
+
+    const SLOTS: usize = 256;
+    // the number of slots tried after the one of a thread, if it's taken
+    const PROBES: usize = 8;
+
+    struct Slot {
+        tid: AtomicI32,
+        stack_pointer: AtomicUsize,
+        low: AtomicUsize,
+        // 0 until the bounds are resolved
+        high: AtomicUsize,
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        tid: AtomicI32::new(0),
+        stack_pointer: AtomicUsize::new(0),
+        low: AtomicUsize::new(0),
+        high: AtomicUsize::new(0),
+    };
+
+    static TABLE: [Slot; SLOTS] = [EMPTY; SLOTS];
+
+    thread_local! {
+        // the id of the current thread, or 0 until it's first needed
+        static TID: Cell<libc::pid_t> = const { Cell::new(0) };
+    }
+
+    fn current_tid() -> libc::pid_t {
+        TID.try_with(|tid| {
+            if tid.get() == 0 {
+                tid.set(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t);
+            }
+            tid.get()
+        })
+        .unwrap_or(0)
+    }
+
+    fn release(slot: &Slot) {
+        slot.stack_pointer.store(0, Ordering::Relaxed);
+        slot.high.store(0, Ordering::Relaxed);
+        slot.tid.store(0, Ordering::Release);
+    }
+
+    /// Returns the bounds of the stack of the current thread once they're
+    /// resolved, and asks for them otherwise. It's called in the signal
+    /// handler.
+    pub fn lookup(stack_pointer: usize) -> Option<(usize, usize)> {
+        let tid = current_tid();
+        if tid == 0 {
+            return None;
+        }
+
+        for probe in 0..PROBES {
+            let slot = &TABLE[(tid as usize + probe) % SLOTS];
+            let owner = slot.tid.load(Ordering::Acquire);
+            if owner == tid {
+                let high = slot.high.load(Ordering::Acquire);
+                if high == 0 {
+                    return None;
+                }
+                let low = slot.low.load(Ordering::Relaxed);
+                release(slot);
+                // the slot may be left by an exited thread with the same id
+                if !(low <= stack_pointer && stack_pointer < high) {
+                    return None;
+                }
+                // the bounds are kept by the thread
+                let _ = BOUNDS.try_with(|cell| cell.set(Some((low, high))));
+                return Some((low, high));
+            }
+            if owner == 0
+                && slot
+                    .tid
+                    .compare_exchange(0, tid, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                slot.stack_pointer.store(stack_pointer, Ordering::Release);
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Fills the bounds of the claimed slots, and releases the ones of the
+    /// threads which exited before taking them.
+    pub fn resolve() {
+        let pending = TABLE.iter().any(|slot| {
+            slot.tid.load(Ordering::Acquire) != 0 && slot.high.load(Ordering::Acquire) == 0
+        });
+        if !pending {
+            return;
+        }
+        let maps = match std::fs::read_to_string("/proc/self/maps") {
+            Ok(maps) => maps,
+            Err(err) => {
+                log::warn!("fail to read /proc/self/maps: {}", err);
+                return;
+            }
+        };
+
+        for slot in TABLE.iter() {
+            let tid = slot.tid.load(Ordering::Acquire);
+            if tid == 0 {
+                continue;
+            }
+            if !std::path::Path::new(&format!("/proc/self/task/{}", tid)).exists() {
+                release(slot);
+                continue;
+            }
+
+            let stack_pointer = slot.stack_pointer.load(Ordering::Acquire);
+            if stack_pointer == 0 || slot.high.load(Ordering::Acquire) != 0 {
+                continue;
+            }
+            if let Some((low, high)) = mapping(&maps, stack_pointer) {
+                slot.low.store(low, Ordering::Relaxed);
+                slot.high.store(high, Ordering::Release);
+            }
+        }
+    }
+
+    /// Returns the readable mapping which contains `addr`, from the lines of
+    /// `/proc/self/maps` like `7f1c2d4e5000-7f1c2d4e7000 rw-p 00000000 00:00 0`.
+    pub(super) fn mapping(maps: &str, addr: usize) -> Option<(usize, usize)> {
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let readable = fields.next()?.starts_with('r');
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            (readable && start <= addr && addr < end).then_some((start, end))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_stack() {
+        let local = 0usize;
+        let stack_pointer = &local as *const usize as usize;
+
+        std::thread::spawn(move || {
+            let local = 0usize;
+            let stack_pointer = &local as *const usize as usize;
+            assert!(Stack::current(stack_pointer).bounds.is_none());
+
+            register_thread();
+            let stack = Stack::current(stack_pointer);
+            if cfg!(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos"
+            )) {
+                assert!(stack.bounds.is_some());
+            }
+            assert!(stack.is_readable(stack_pointer, 8));
+            // below the stack pointer
+            assert!(stack.bounds.is_none() || !stack.is_readable(stack_pointer - 64, 8));
+        })
+        .join()
+        .unwrap();
+
+        // the stack pointer of another thread is not on the stack of this one
+        let stack = std::thread::spawn(move || {
+            register_thread();
+            Stack::current(stack_pointer).bounds
+        })
+        .join()
+        .unwrap();
+        assert!(stack.is_none());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn unregistered_stack() {
+        std::thread::spawn(|| {
+            let local = 0usize;
+            let stack_pointer = &local as *const usize as usize;
+
+            // the first sample asks for the bounds
+            assert!(Stack::current(stack_pointer).bounds.is_none());
+            resolve_stacks();
+
+            let stack = Stack::current(stack_pointer);
+            assert!(stack.bounds.is_some());
+            assert!(stack.is_readable(stack_pointer, 8));
+            // they're kept by the thread
+            assert!(BOUNDS.with(Cell::get).is_some());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn mapping_of_address() {
+        let maps = "00400000-00452000 r-xp 00000000 08:02 173521 /usr/bin/dbus-daemon\n\
+                    7f1c2d4e5000-7f1c2d4e7000 rw-p 00000000 00:00 0\n\
+                    7f1c2d4e7000-7f1c2d4e8000 ---p 00000000 00:00 0\n";
+        assert_eq!(
+            lazy::mapping(maps, 0x7f1c2d4e6000),
+            Some((0x7f1c2d4e5000, 0x7f1c2d4e7000))
+        );
+        assert_eq!(lazy::mapping(maps, 0x7f1c2d4e7800), None);
+        assert_eq!(lazy::mapping(maps, 0x1000), None);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::path::PathBuf;
+
+use findshlibs::{SharedLibrary, TargetSharedLibrary};
+use memmap2::Mmap;
+use object::{Object, ObjectSymbol, SymbolKind};
+use symbolic_demangle::demangle;
+
+use crate::error::{Error, Result};
+
+/// What the profiler does with a sample which has a blocklisted function in
+/// its stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlocklistAction {
+    /// The sample is dropped, and counted in `SampleStats::dropped_blocklisted`.
+    DropSample,
+
+    /// The frames called by the function are removed, so that the sample ends
+    /// at it. The time spent inside it, e.g. in the internals of an allocator,
+    /// is attributed to the function itself.
+    TruncateStack,
+}
+
+/// The address range of a blocklisted function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FunctionRange {
+    start: usize,
+    end: usize,
+    action: BlocklistAction,
+}
+
+/// The address ranges of the blocklisted functions, sorted and without
+/// overlaps, so that they can be looked up in the signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionBlocklist {
+    ranges: Vec<FunctionRange>,
+}
+
+impl FunctionBlocklist {
+    /// Resolves the functions whose demangled name contains one of the
+    /// patterns, from the symbol tables of the loaded libraries and of the
+    /// executable. It fails if a pattern matches no function.
+    pub fn resolve(patterns: &[(String, BlocklistAction)]) -> Result<FunctionBlocklist> {
+        let (blocklist, matched) = FunctionBlocklist::find(patterns);
+        if let Some(((pattern, _), _)) = patterns.iter().zip(matched).find(|(_, matched)| !matched)
+        {
+            return Err(Error::UnmatchedBlocklist(pattern.clone()));
+        }
+
+        Ok(blocklist)
+    }
+
+    /// Resolves the functions again, e.g. after a library was loaded. The
+    /// patterns which match no function anymore are ignored.
+    pub fn refresh(patterns: &[(String, BlocklistAction)]) -> FunctionBlocklist {
+        FunctionBlocklist::find(patterns).0
+    }
+
+    /// Returns the ranges of the functions, and whether each pattern matched
+    /// one of them.
+    fn find(patterns: &[(String, BlocklistAction)]) -> (FunctionBlocklist, Vec<bool>) {
+        if patterns.is_empty() {
+            return (FunctionBlocklist::default(), Vec::new());
+        }
+
+        let mut matched = vec![false; patterns.len()];
+        let mut ranges = Vec::new();
+        TargetSharedLibrary::each(|shlib| {
+            let path = match shlib.name().to_str() {
+                Some("") | None => PathBuf::from("/proc/self/exe"),
+                Some(name) => PathBuf::from(name),
+            };
+            // e.g. the vdso, which has no file
+            let mmap = match open_mmap(&path) {
+                Some(mmap) => mmap,
+                None => return,
+            };
+            let file = match object::File::parse(&*mmap) {
+                Ok(file) => file,
+                Err(_) => return,
+            };
+
+            let bias = shlib.virtual_memory_bias().0;
+            for symbol in file.symbols().chain(file.dynamic_symbols()) {
+                if symbol.kind() != SymbolKind::Text || symbol.size() == 0 {
+                    continue;
+                }
+                let name = match symbol.name() {
+                    Ok(name) => demangle(name),
+                    Err(_) => continue,
+                };
+
+                let pattern = patterns
+                    .iter()
+                    .zip(matched.iter_mut())
+                    .find(|((pattern, _), _)| name.contains(pattern.as_str()));
+                if let Some(((_, action), matched)) = pattern {
+                    *matched = true;
+                    let start = bias.wrapping_add(symbol.address() as usize);
+                    ranges.push(FunctionRange {
+                        start,
+                        end: start + symbol.size() as usize,
+                        action: *action,
+                    });
+                }
+            }
+        });
+
+        (FunctionBlocklist::from_ranges(ranges), matched)
+    }
+
+    fn from_ranges(mut ranges: Vec<FunctionRange>) -> FunctionBlocklist {
+        // The symbol table and the dynamic one list the same functions, and
+        // aliases overlap. The overlapping ranges are merged, and dropping the
+        // sample wins over truncating it.
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<FunctionRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start < last.end => {
+                    last.end = last.end.max(range.end);
+                    if range.action == BlocklistAction::DropSample {
+                        last.action = BlocklistAction::DropSample;
+                    }
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        FunctionBlocklist { ranges: merged }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the action for the function containing `addr`, if it's
+    /// blocklisted. It doesn't allocate, so it can be called in the signal
+    /// handler.
+    pub fn lookup(&self, addr: usize) -> Option<BlocklistAction> {
+        let index = self.ranges.partition_point(|range| range.start <= addr);
+        let range = self.ranges.get(index.checked_sub(1)?)?;
+        (addr < range.end).then_some(range.action)
+    }
+}
+
+/// Collects the frames of a sample, as the unwinder gives them from the leaf,
+/// up to `max_depth`. While there are blocklisted functions, the frames beyond
+/// it are still looked up, so that a deeper one drops or truncates the sample.
+pub struct StackWalk<'a> {
+    blocklist: &'a FunctionBlocklist,
+    max_depth: usize,
+    // the number of frames walked, including the ones which were left out
+    depth: usize,
+    pub dropped: bool,
+}
+
+impl<'a> StackWalk<'a> {
+    pub fn new(blocklist: &'a FunctionBlocklist, max_depth: usize) -> Self {
+        StackWalk {
+            blocklist,
+            max_depth,
+            depth: 0,
+            dropped: false,
+        }
+    }
+
+    /// Adds the frame at `ip` to `frames`, and returns whether the walk goes
+    /// on. It doesn't allocate as long as `frames` can hold `max_depth`.
+    pub fn visit<T: Clone>(&mut self, frames: &mut Vec<T>, frame: &T, ip: usize) -> bool {
+        let leaf = self.depth == 0;
+        self.depth += 1;
+
+        if self.blocklist.is_empty() {
+            if frames.len() >= self.max_depth {
+                return false;
+            }
+        } else {
+            // the callers are at their return address, which may be the first
+            // instruction of the next function
+            let addr = if leaf { ip } else { ip.wrapping_sub(1) };
+            match self.blocklist.lookup(addr) {
+                Some(BlocklistAction::DropSample) => {
+                    self.dropped = true;
+                    return false;
+                }
+                Some(BlocklistAction::TruncateStack) => frames.clear(),
+                None => {}
+            }
+        }
+
+        if frames.len() < self.max_depth {
+            frames.push(frame.clone());
+        }
+        true
+    }
+}
+
+fn open_mmap(path: &PathBuf) -> Option<Mmap> {
+    let file = std::fs::File::open(path).ok()?;
+    let mmap = unsafe { Mmap::map(&file) }.ok()?;
+    Some(mmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn blocklisted_function() -> usize {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn resolve_function() {
+        let patterns = vec![(
+            "blocklist::tests::blocklisted_function".to_owned(),
+            BlocklistAction::TruncateStack,
+        )];
+        let blocklist = FunctionBlocklist::resolve(&patterns).unwrap();
+
+        assert_eq!(blocklisted_function(), 42);
+        let addr = blocklisted_function as fn() -> usize as usize;
+        assert_eq!(blocklist.lookup(addr), Some(BlocklistAction::TruncateStack));
+        assert_eq!(blocklist.lookup(resolve_function as fn() as usize), None);
+
+        let patterns = vec![("no::such::function".to_owned(), BlocklistAction::DropSample)];
+        assert!(matches!(
+            FunctionBlocklist::resolve(&patterns),
+            Err(Error::UnmatchedBlocklist(pattern)) if pattern == "no::such::function"
+        ));
+    }
+
+    #[test]
+    fn merge_overlapping_ranges() {
+        let range = |start, end, action| FunctionRange { start, end, action };
+        let blocklist = FunctionBlocklist::from_ranges(vec![
+            range(300, 400, BlocklistAction::TruncateStack),
+            range(100, 200, BlocklistAction::TruncateStack),
+            range(150, 250, BlocklistAction::DropSample),
+        ]);
+
+        assert_eq!(blocklist.lookup(99), None);
+        assert_eq!(blocklist.lookup(100), Some(BlocklistAction::DropSample));
+        assert_eq!(blocklist.lookup(249), Some(BlocklistAction::DropSample));
+        assert_eq!(blocklist.lookup(250), None);
+        assert_eq!(blocklist.lookup(399), Some(BlocklistAction::TruncateStack));
+        assert_eq!(blocklist.lookup(400), None);
+    }
+
+    #[test]
+    fn walk_beyond_max_depth() {
+        let range = |start, end, action| FunctionRange { start, end, action };
+        let blocklist = FunctionBlocklist::from_ranges(vec![
+            range(2000, 2100, BlocklistAction::TruncateStack),
+            range(3000, 3100, BlocklistAction::DropSample),
+        ]);
+        let walk = |blocklist: &FunctionBlocklist, ips: &[usize]| {
+            let mut frames = Vec::with_capacity(3);
+            let mut walk = StackWalk::new(blocklist, 3);
+            for ip in ips {
+                if !walk.visit(&mut frames, ip, *ip) {
+                    break;
+                }
+            }
+            (frames, walk.dropped)
+        };
+
+        // a function below the maximum depth still truncates the stack, or
+        // drops the sample
+        assert_eq!(
+            walk(&blocklist, &[10, 20, 30, 40, 2051, 60]),
+            (vec![2051, 60], false)
+        );
+        assert!(walk(&blocklist, &[10, 20, 30, 40, 3051, 60]).1);
+        assert_eq!(
+            walk(&blocklist, &[10, 20, 30, 40, 50]),
+            (vec![10, 20, 30], false)
+        );
+
+        // the callers are looked up before their return address
+        assert_eq!(walk(&blocklist, &[2100, 2100]), (vec![2100], false));
+        assert_eq!(
+            walk(&FunctionBlocklist::default(), &[10, 20, 30, 40]),
+            (vec![10, 20, 30], false)
+        );
+    }
+}
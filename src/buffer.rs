@@ -0,0 +1,350 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::UnsafeCell;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The minimum number of samples a shard can hold until it's drained.
+pub const SHARD_CAPACITY: usize = 32;
+
+/// The maximum number of samples a shard can hold, which bounds the memory
+/// reserved for the highest frequencies.
+pub const MAX_SHARD_CAPACITY: usize = 1024;
+
+/// The maximum number of shards, which is the number of CPUs otherwise.
+pub const MAX_SHARDS: usize = 64;
+
+/// How often the aggregator drains the buffers.
+pub const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Slot<T> {
+    // `position + 1` once the item of `position` is written, and `position +
+    // capacity` once it's read, i.e. when it can be written again
+    sequence: AtomicUsize,
+    item: UnsafeCell<T>,
+    // whether the writer kept the item, or dropped the sample
+    filled: UnsafeCell<bool>,
+}
+
+impl<T> Slot<T> {
+    /// Writes the item of a claimed slot with `fill`, and releases it to the
+    /// reader.
+    fn write(&self, position: usize, fill: impl FnOnce(&mut T) -> bool) {
+        // the slot is only accessed by its claimer until it's released
+        unsafe { *self.filled.get() = fill(&mut *self.item.get()) };
+        self.sequence
+            .store(position.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// A bounded queue, in which a slot is claimed by moving a position forward
+/// with a compare-and-swap, and released by its sequence number. A writer
+/// never waits for another one, so it can be used in the signal handler.
+struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl<T> Ring<T> {
+    fn new<F: FnMut() -> T>(capacity: usize, new_item: &mut F) -> Ring<T> {
+        let capacity = capacity.next_power_of_two();
+        let slots = (0..capacity)
+            .map(|position| Slot {
+                sequence: AtomicUsize::new(position),
+                item: UnsafeCell::new(new_item()),
+                filled: UnsafeCell::new(false),
+            })
+            .collect();
+
+        Ring {
+            slots,
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claims the next slot, or returns `None` if the ring is full.
+    fn claim(&self) -> Option<(&Slot<T>, usize)> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(position as isize) {
+                0 => match self.head.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some((slot, position)),
+                    Err(current) => position = current,
+                },
+                // the slot wasn't read since the last round
+                diff if diff < 0 => return None,
+                _ => position = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Calls `f` with the next item, and returns whether there was one. An
+    /// item whose writer is still running is left for the next time.
+    fn pop(&self, f: &mut impl FnMut(&T)) -> bool {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(position.wrapping_add(1) as isize) {
+                0 => match self.tail.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        if unsafe { *slot.filled.get() } {
+                            f(unsafe { &*slot.item.get() });
+                        }
+                        slot.sequence
+                            .store(position.wrapping_add(self.mask + 1), Ordering::Release);
+                        return true;
+                    }
+                    Err(current) => position = current,
+                },
+                diff if diff < 0 => return false,
+                _ => position = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+/// The buffers the signal handler writes the samples into, with a ring per CPU
+/// so that the handlers running at the same time rarely touch the same one.
+/// Their items are created in advance, and reused once they're drained.
+pub struct SampleBuffers<T> {
+    shards: Box<[Ring<T>]>,
+}
+
+// The items are only accessed by the writer or the reader which claimed them.
+unsafe impl<T: Send> Send for SampleBuffers<T> {}
+unsafe impl<T: Send> Sync for SampleBuffers<T> {}
+
+/// Returns the number of samples a shard holds for the samples taken at
+/// `frequency`. A CPU runs one thread at a time, so it takes at most
+/// `frequency` samples per second, and the ones of two drain intervals fit in
+/// its shard, in case the aggregator is late.
+pub fn shard_capacity(frequency: c_int) -> usize {
+    let per_interval =
+        (frequency.max(0) as u128 * DRAIN_INTERVAL.as_nanos()).div_ceil(1_000_000_000);
+    (2 * per_interval as usize).clamp(SHARD_CAPACITY, MAX_SHARD_CAPACITY)
+}
+
+impl<T> SampleBuffers<T> {
+    /// Creates a ring per CPU, which holds `capacity` samples.
+    pub fn new_with<F: FnMut() -> T>(capacity: usize, mut new_item: F) -> SampleBuffers<T> {
+        let shards = std::thread::available_parallelism()
+            .map_or(1, |parallelism| parallelism.get())
+            .min(MAX_SHARDS);
+        SampleBuffers::with_shards(shards, capacity, &mut new_item)
+    }
+
+    fn with_shards<F: FnMut() -> T>(
+        shards: usize,
+        capacity: usize,
+        new_item: &mut F,
+    ) -> SampleBuffers<T> {
+        let shards = (0..shards).map(|_| Ring::new(capacity, new_item)).collect();
+        SampleBuffers { shards }
+    }
+
+    /// Writes a sample into the ring of the current CPU with `fill`, which
+    /// returns whether it's kept. The next rings are tried if it's full, and
+    /// `false` is returned if they all are. It's called in the signal handler.
+    pub fn push(&self, fill: impl FnOnce(&mut T) -> bool) -> bool {
+        let first = current_cpu() % self.shards.len();
+        let mut fill = Some(fill);
+        for index in 0..self.shards.len() {
+            let shard = &self.shards[(first + index) % self.shards.len()];
+            if let Some((slot, position)) = shard.claim() {
+                slot.write(position, fill.take().unwrap());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Calls `f` with every sample which was written, up to a round of each
+    /// ring, so that it returns even if they're written continuously.
+    pub fn drain(&self, mut f: impl FnMut(&T)) {
+        for shard in self.shards.iter() {
+            for _ in 0..shard.slots.len() {
+                if !shard.pop(&mut f) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the CPU the current thread runs on, or another number which is
+/// stable for the thread if it's unknown.
+fn current_cpu() -> usize {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu >= 0 {
+            return cpu as usize;
+        }
+    }
+
+    // the thread structures are aligned, so the low bits are all the same
+    (unsafe { libc::pthread_self() } as usize) >> 12
+}
+
+/// A background thread which drains the sample buffers into the collector, so
+/// that the signal handler never waits for it.
+pub struct Aggregator {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Aggregator {
+    /// Spawns a thread which calls `drain` every `DRAIN_INTERVAL`, and once
+    /// more when it's stopped. It's given whether it's the last call.
+    pub fn spawn<F: FnMut(bool) + Send + 'static>(mut drain: F) -> std::io::Result<Aggregator> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-aggregator".to_owned())
+                .spawn(move || {
+                    let _own_thread = crate::timer::register_own_thread();
+                    loop {
+                        std::thread::park_timeout(DRAIN_INTERVAL);
+                        let stopped = stop.load(Ordering::SeqCst);
+                        drain(stopped);
+                        if stopped {
+                            break;
+                        }
+                    }
+                })?
+        };
+
+        Ok(Aggregator {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stops the background thread, once it has drained the buffers.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Aggregator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn push_and_drain() {
+        let buffers = SampleBuffers::with_shards(2, 4, &mut || 0usize);
+
+        // the rings are filled one after the other
+        for value in 0..8 {
+            assert!(buffers.push(|item| {
+                *item = value;
+                // the odd samples are dropped
+                value % 2 == 0
+            }));
+        }
+        assert!(!buffers.push(|_| true));
+
+        let mut values = Vec::new();
+        buffers.drain(|item| values.push(*item));
+        values.sort_unstable();
+        assert_eq!(values, vec![0, 2, 4, 6]);
+
+        // the slots are reused
+        assert!(buffers.push(|item| {
+            *item = 8;
+            true
+        }));
+        let mut values = Vec::new();
+        buffers.drain(|item| values.push(*item));
+        assert_eq!(values, vec![8]);
+    }
+
+    #[test]
+    fn capacity_of_frequency() {
+        assert_eq!(shard_capacity(99), SHARD_CAPACITY);
+        // the samples of two intervals
+        assert_eq!(shard_capacity(10_000), 200);
+        assert_eq!(shard_capacity(crate::MAX_FREQUENCY), MAX_SHARD_CAPACITY);
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        const WRITERS: usize = 4;
+        const SAMPLES: usize = 10_000;
+
+        let buffers = Arc::new(SampleBuffers::with_shards(2, 8, &mut || (0usize, 0usize)));
+        let drained = Arc::new(Mutex::new(Vec::new()));
+        let mut aggregator = {
+            let (buffers, drained) = (buffers.clone(), drained.clone());
+            Aggregator::spawn(move |_| {
+                let mut drained = drained.lock().unwrap();
+                buffers.drain(|item| drained.push(*item));
+            })
+            .unwrap()
+        };
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let buffers = buffers.clone();
+                std::thread::spawn(move || {
+                    let mut written = Vec::new();
+                    for sample in 0..SAMPLES {
+                        let pushed = buffers.push(|item| {
+                            *item = (writer, sample);
+                            true
+                        });
+                        if pushed {
+                            written.push((writer, sample));
+                        } else {
+                            std::thread::yield_now();
+                        }
+                    }
+                    written
+                })
+            })
+            .collect();
+        let mut written: Vec<_> = writers
+            .into_iter()
+            .flat_map(|writer| writer.join().unwrap())
+            .collect();
+        aggregator.stop();
+
+        // every sample which was pushed is drained exactly once
+        let mut drained = drained.lock().unwrap().clone();
+        written.sort_unstable();
+        drained.sort_unstable();
+        assert_eq!(drained, written);
+    }
+}
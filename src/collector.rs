@@ -5,15 +5,89 @@ use std::convert::TryInto;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::mem::size_of;
 
-use crate::frames::UnresolvedFrames;
-
-use aligned_vec::AVec;
 use tempfile::NamedTempFile;
 
 pub const BUCKETS: usize = 1 << 12;
 pub const BUCKETS_ASSOCIATIVITY: usize = 4;
-pub const BUFFER_LENGTH: usize = (1 << 18) / std::mem::size_of::<Entry<UnresolvedFrames>>();
+/// Size in bytes of the in-memory buffer in front of the temporary file.
+pub const BUFFER_SIZE: usize = 1 << 18;
+
+/// An item which can be written into the temporary file of a `Collector`, as a
+/// record of fixed size.
+///
+/// `write_record` may be called inside the signal handler, so it must not
+/// allocate.
+pub trait Spill: Sized {
+    /// Size in bytes of the record. All items of a collector are expected to
+    /// have the same record size as the one it is created with.
+    fn record_size(&self) -> usize;
+
+    /// Writes `self` into `buf`, which has `record_size()` bytes.
+    fn write_record(&self, buf: &mut [u8]);
+
+    /// Reads an item back from a record written by `write_record`.
+    fn read_record(buf: &[u8]) -> Self;
+}
+
+impl<T: Copy> Spill for T {
+    fn record_size(&self) -> usize {
+        size_of::<T>()
+    }
+
+    fn write_record(&self, buf: &mut [u8]) {
+        let mut offset = 0;
+        write_value(buf, &mut offset, *self);
+    }
+
+    fn read_record(buf: &[u8]) -> Self {
+        let mut offset = 0;
+        read_value(buf, &mut offset)
+    }
+}
+
+/// Copies `value` into `buf` at `offset`, and moves `offset` past it.
+pub(crate) fn write_value<V: Copy>(buf: &mut [u8], offset: &mut usize, value: V) {
+    write_raw(buf, offset, std::slice::from_ref(&value));
+}
+
+/// Reads a value written by `write_value`, and moves `offset` past it.
+pub(crate) fn read_value<V: Copy>(buf: &[u8], offset: &mut usize) -> V {
+    let bytes = &buf[*offset..*offset + size_of::<V>()];
+    *offset += size_of::<V>();
+    unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const V) }
+}
+
+/// Copies the bytes of `values` into `buf` at `offset`, and moves `offset`
+/// past them. The values are read back with `read_raw`.
+pub(crate) fn write_raw<V>(buf: &mut [u8], offset: &mut usize, values: &[V]) {
+    let length = std::mem::size_of_val(values);
+    let bytes = unsafe { std::slice::from_raw_parts(values.as_ptr() as *const u8, length) };
+    buf[*offset..*offset + length].copy_from_slice(bytes);
+    *offset += length;
+}
+
+/// Reads `length` values written by `write_raw` into `values`, and moves
+/// `offset` past them.
+///
+/// # Safety
+///
+/// The bytes must be a valid bitwise copy of `V`s, which are still valid
+/// after being copied.
+pub(crate) unsafe fn read_raw<V>(
+    buf: &[u8],
+    offset: &mut usize,
+    length: usize,
+    values: &mut Vec<V>,
+) {
+    let bytes = &buf[*offset..*offset + length * size_of::<V>()];
+    *offset += length * size_of::<V>();
+    values.extend(
+        (0..length).map(|index| std::ptr::read_unaligned((bytes.as_ptr() as *const V).add(index))),
+    );
+}
 
 #[derive(Debug)]
 pub struct Entry<T> {
@@ -44,25 +118,48 @@ impl<T: Eq + Default> Default for Bucket<T> {
     }
 }
 
-impl<T: Eq> Bucket<T> {
-    pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
+impl<T> Bucket<T> {
+    /// Creates a bucket whose entries are created by `new_item`, so that their
+    /// storage can be reserved in advance.
+    pub fn new_with<F: FnMut() -> T>(new_item: &mut F) -> Bucket<T> {
+        let entries = Box::new(std::array::from_fn(|_| Entry {
+            item: new_item(),
+            count: 0,
+        }));
+
+        Self { length: 0, entries }
+    }
+
+    pub fn iter(&self) -> BucketIterator<'_, T> {
+        BucketIterator::<T> {
+            related_bucket: self,
+            index: 0,
+        }
+    }
+}
+
+impl<T: Eq + Clone> Bucket<T> {
+    /// Adds `count` to `key`. When the bucket is full, the entry with the
+    /// minimum count is passed to `evict` and then replaced by `key`.
+    ///
+    /// The key is copied through `clone_from`, so that the storage of the
+    /// entries is reused instead of being allocated again.
+    pub fn add<F: FnOnce(&Entry<T>)>(&mut self, key: &T, count: isize, evict: F) {
         let mut done = false;
         self.entries[0..self.length].iter_mut().for_each(|ele| {
-            if ele.item == key {
+            if ele.item == *key {
                 ele.count += count;
                 done = true;
             }
         });
 
         if done {
-            None
-        } else if self.length < BUCKETS_ASSOCIATIVITY {
-            let ele = &mut self.entries[self.length];
-            ele.item = key;
-            ele.count = count;
+            return;
+        }
 
+        let index = if self.length < BUCKETS_ASSOCIATIVITY {
             self.length += 1;
-            None
+            self.length - 1
         } else {
             let mut min_index = 0;
             let mut min_count = self.entries[0].count;
@@ -74,17 +171,13 @@ impl<T: Eq> Bucket<T> {
                 }
             }
 
-            let mut new_entry = Entry { item: key, count };
-            std::mem::swap(&mut self.entries[min_index], &mut new_entry);
-            Some(new_entry)
-        }
-    }
+            evict(&self.entries[min_index]);
+            min_index
+        };
 
-    pub fn iter(&self) -> BucketIterator<T> {
-        BucketIterator::<T> {
-            related_bucket: self,
-            index: 0,
-        }
+        let ele = &mut self.entries[index];
+        ele.item.clone_from(key);
+        ele.count = count;
     }
 }
 
@@ -120,20 +213,35 @@ impl<T: Hash + Eq + Default + Debug> Default for HashCounter<T> {
     }
 }
 
-impl<T: Hash + Eq> HashCounter<T> {
+impl<T: Hash + Eq + Debug> HashCounter<T> {
+    /// Creates a counter whose entries are created by `new_item`. See
+    /// [`Collector::new_with`].
+    pub fn new_with<F: FnMut() -> T>(mut new_item: F) -> Self {
+        let mut v: Vec<Bucket<T>> = Vec::with_capacity(BUCKETS);
+        v.resize_with(BUCKETS, || Bucket::new_with(&mut new_item));
+        let buckets = v.into_boxed_slice().try_into().unwrap();
+
+        Self { buckets }
+    }
+}
+
+impl<T: Hash + Eq + Clone> HashCounter<T> {
     fn hash(key: &T) -> u64 {
         let mut s = DefaultHasher::new();
         key.hash(&mut s);
         s.finish()
     }
 
-    pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
-        let hash_value = Self::hash(&key);
+    /// Adds `count` to `key`. See [`Bucket::add`] for the eviction.
+    pub fn add<F: FnOnce(&Entry<T>)>(&mut self, key: &T, count: isize, evict: F) {
+        let hash_value = Self::hash(key);
         let bucket = &mut self.buckets[(hash_value % BUCKETS as u64) as usize];
 
-        bucket.add(key, count)
+        bucket.add(key, count, evict)
     }
+}
 
+impl<T: Hash + Eq> HashCounter<T> {
     pub fn iter(&self) -> impl Iterator<Item = &Entry<T>> {
         let mut iter: Box<dyn Iterator<Item = &Entry<T>>> =
             Box::new(self.buckets[0].iter().chain(std::iter::empty()));
@@ -147,127 +255,157 @@ impl<T: Hash + Eq> HashCounter<T> {
 
 pub struct TempFdArray<T: 'static> {
     file: NamedTempFile,
-    buffer: Box<[T; BUFFER_LENGTH]>,
+    buffer: Box<[u8]>,
+    record_size: usize,
+    buffer_length: usize,
     buffer_index: usize,
     flush_n: usize,
+    _marker: PhantomData<T>,
 }
 
-impl<T: Default + Debug> TempFdArray<T> {
-    fn new() -> std::io::Result<TempFdArray<T>> {
+impl<T> TempFdArray<T> {
+    /// Creates an array of entries, each of which is stored as a record of
+    /// `item_size` bytes plus its count.
+    fn new(item_size: usize) -> std::io::Result<TempFdArray<T>> {
         let file = NamedTempFile::new()?;
 
-        let mut v: Vec<T> = Vec::with_capacity(BUFFER_LENGTH);
-        v.resize_with(BUFFER_LENGTH, Default::default);
-        let buffer = v.into_boxed_slice().try_into().unwrap();
+        let record_size = size_of::<isize>() + item_size;
+        let buffer_length = std::cmp::max(BUFFER_SIZE / record_size, 1);
+        let buffer = vec![0; buffer_length * record_size].into_boxed_slice();
 
         Ok(Self {
             file,
             buffer,
+            record_size,
+            buffer_length,
             buffer_index: 0,
             flush_n: 0,
+            _marker: PhantomData,
         })
     }
 }
 
-impl<T> TempFdArray<T> {
+impl<T: Spill> TempFdArray<T> {
     fn flush_buffer(&mut self) -> std::io::Result<()> {
         self.buffer_index = 0;
-        let buf = unsafe {
-            std::slice::from_raw_parts(
-                self.buffer.as_ptr() as *const u8,
-                BUFFER_LENGTH * std::mem::size_of::<T>(),
-            )
-        };
         self.flush_n += 1;
-        self.file.write_all(buf)?;
+        self.file.write_all(&self.buffer)?;
 
         Ok(())
     }
 
-    fn push(&mut self, entry: T) -> std::io::Result<()> {
-        if self.buffer_index >= BUFFER_LENGTH {
+    fn push(&mut self, entry: &Entry<T>) -> std::io::Result<()> {
+        if self.buffer_index >= self.buffer_length {
             self.flush_buffer()?;
         }
 
-        self.buffer[self.buffer_index] = entry;
+        let start = self.buffer_index * self.record_size;
+        let record = &mut self.buffer[start..start + self.record_size];
+        let mut offset = 0;
+        write_value(record, &mut offset, entry.count);
+        entry.item.write_record(&mut record[offset..]);
         self.buffer_index += 1;
 
         Ok(())
     }
 
-    fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &T>> {
-        let size = BUFFER_LENGTH * self.flush_n * std::mem::size_of::<T>();
+    fn try_iter(&self) -> std::io::Result<impl Iterator<Item = Entry<T>> + '_> {
+        let size = self.buffer.len() * self.flush_n;
 
-        let mut file_vec = AVec::with_capacity(std::mem::align_of::<T>(), size);
+        let mut file_vec = vec![0; size];
         let mut file = self.file.reopen()?;
 
-        unsafe {
-            // it's safe as the capacity is initialized to `size`, and it'll be filled with `size` bytes
-            file_vec.set_len(size);
-        }
         file.read_exact(&mut file_vec[0..size])?;
         file.seek(SeekFrom::End(0))?;
 
         Ok(TempFdArrayIterator {
-            buffer: &self.buffer[0..self.buffer_index],
+            buffer: &self.buffer[0..self.buffer_index * self.record_size],
             file_vec,
+            record_size: self.record_size,
             index: 0,
+            _marker: PhantomData,
         })
     }
 }
 
 pub struct TempFdArrayIterator<'a, T> {
-    pub buffer: &'a [T],
-    pub file_vec: AVec<u8>,
+    pub buffer: &'a [u8],
+    pub file_vec: Vec<u8>,
+    pub record_size: usize,
     pub index: usize,
+    _marker: PhantomData<T>,
 }
 
-impl<'a, T> Iterator for TempFdArrayIterator<'a, T> {
-    type Item = &'a T;
+impl<'a, T: Spill> Iterator for TempFdArrayIterator<'a, T> {
+    type Item = Entry<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.buffer.len() {
-            self.index += 1;
-            Some(&self.buffer[self.index - 1])
+        let buffer_length = self.buffer.len() / self.record_size;
+        let file_length = self.file_vec.len() / self.record_size;
+
+        let record = if self.index < buffer_length {
+            &self.buffer[self.index * self.record_size..]
+        } else if self.index - buffer_length < file_length {
+            &self.file_vec[(self.index - buffer_length) * self.record_size..]
         } else {
-            let length = self.file_vec.len() / std::mem::size_of::<T>();
-            let ts =
-                unsafe { std::slice::from_raw_parts(self.file_vec.as_ptr() as *const T, length) };
-            if self.index - self.buffer.len() < ts.len() {
-                self.index += 1;
-                Some(&ts[self.index - self.buffer.len() - 1])
-            } else {
-                None
-            }
-        }
+            return None;
+        };
+        self.index += 1;
+
+        let mut offset = 0;
+        let count = read_value(record, &mut offset);
+        let item = T::read_record(&record[offset..self.record_size]);
+        Some(Entry { item, count })
     }
 }
 
 pub struct Collector<T: Hash + Eq + 'static> {
     map: HashCounter<T>,
-    temp_array: TempFdArray<Entry<T>>,
+    temp_array: TempFdArray<T>,
 }
 
-impl<T: Hash + Eq + Default + Debug + 'static> Collector<T> {
+impl<T: Hash + Eq + Default + Debug + Spill + 'static> Collector<T> {
     pub fn new() -> std::io::Result<Self> {
+        Self::new_with(T::default)
+    }
+}
+
+impl<T: Hash + Eq + Debug + Spill + 'static> Collector<T> {
+    /// Creates a collector whose entries are created by `new_item` in
+    /// advance. Keys are copied into them with `clone_from`, so an item whose
+    /// storage is reserved here can be added without allocating. The record
+    /// size of the temporary file is taken from the first item.
+    pub fn new_with<F: FnMut() -> T>(mut new_item: F) -> std::io::Result<Self> {
+        let item_size = new_item().record_size();
+
         Ok(Self {
-            map: HashCounter::<T>::default(),
-            temp_array: TempFdArray::<Entry<T>>::new()?,
+            map: HashCounter::new_with(new_item),
+            temp_array: TempFdArray::new(item_size)?,
         })
     }
 }
 
-impl<T: Hash + Eq + 'static> Collector<T> {
-    pub fn add(&mut self, key: T, count: isize) -> std::io::Result<()> {
-        if let Some(evict) = self.map.add(key, count) {
-            self.temp_array.push(evict)?;
-        }
+impl<T: Hash + Eq + Clone + Spill + 'static> Collector<T> {
+    /// Adds `count` to `key`. An entry evicted from the map is written into
+    /// the temporary file.
+    pub fn add(&mut self, key: &T, count: isize) -> std::io::Result<()> {
+        let mut result = Ok(());
+        let temp_array = &mut self.temp_array;
+        self.map
+            .add(key, count, |evict| result = temp_array.push(evict));
 
-        Ok(())
+        result
     }
 
-    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &Entry<T>>> {
-        Ok(self.map.iter().chain(self.temp_array.try_iter()?))
+    /// Iterates over the entries of the map and of the temporary file. The
+    /// ones of the file are read back, so the entries are owned.
+    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = Entry<T>> + '_> {
+        let entries = self.map.iter().map(|entry| Entry {
+            item: entry.item.clone(),
+            count: entry.count,
+        });
+
+        Ok(entries.chain(self.temp_array.try_iter()?))
     }
 }
 
@@ -294,9 +432,9 @@ mod tests {
     #[test]
     fn stack_hash_counter() {
         let mut stack_hash_counter = HashCounter::<usize>::default();
-        stack_hash_counter.add(0, 1);
-        stack_hash_counter.add(1, 1);
-        stack_hash_counter.add(1, 1);
+        stack_hash_counter.add(&0, 1, |_| unreachable!());
+        stack_hash_counter.add(&1, 1, |_| unreachable!());
+        stack_hash_counter.add(&1, 1, |_| unreachable!());
 
         stack_hash_counter.iter().for_each(|item| {
             if item.item == 0 {
@@ -316,12 +454,9 @@ mod tests {
 
         for item in 0..(1 << 10) * 4 {
             for _ in 0..(item % 4) {
-                match stack_hash_counter.add(item, 1) {
-                    None => {}
-                    Some(evict) => {
-                        test_utils::add_map(&mut real_map, &evict);
-                    }
-                }
+                stack_hash_counter.add(&item, 1, |evict| {
+                    test_utils::add_map(&mut real_map, evict);
+                });
             }
         }
 
@@ -349,12 +484,12 @@ mod tests {
 
         for item in 0..(1 << 12) * 4 {
             for _ in 0..(item % 4) {
-                collector.add(item, 1).unwrap();
+                collector.add(&item, 1).unwrap();
             }
         }
 
         collector.try_iter().unwrap().for_each(|entry| {
-            test_utils::add_map(&mut real_map, entry);
+            test_utils::add_map(&mut real_map, &entry);
         });
 
         for item in 0..(1 << 12) * 4 {
@@ -388,7 +523,7 @@ mod tests {
             for _ in 0..(item % 4) {
                 collector
                     .add(
-                        AlignTest {
+                        &AlignTest {
                             a: item as u16,
                             b: item as u32,
                             c: item as u64,
@@ -401,7 +536,7 @@ mod tests {
         }
 
         collector.try_iter().unwrap().for_each(|entry| {
-            test_utils::add_map(&mut real_map, entry);
+            test_utils::add_map(&mut real_map, &entry);
         });
 
         for item in 0..(1 << 12) * 4 {
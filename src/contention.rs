@@ -0,0 +1,370 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A lock-contention profiler.
+//!
+//! [`Mutex`] and [`RwLock`] wrap the locks of the standard library. While a
+//! [`ContentionProfilerGuard`] is alive, every acquisition which had to wait
+//! for at least the threshold is recorded with its stack, and weighted by the
+//! time it waited, like the mutex and block profiles of Go.
+//!
+//! ```
+//! use pprof::contention::{ContentionProfilerGuardBuilder, Mutex};
+//!
+//! static COUNTER: Mutex<u64> = Mutex::new(0);
+//!
+//! let guard = ContentionProfilerGuardBuilder::default().build().unwrap();
+//! *COUNTER.lock().unwrap() += 1;
+//! let report = guard.report().unwrap();
+//! println!("{:?}", report);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    LockResult, MutexGuard, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::Result;
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::report::events::{self, EventCounts, EventProfiler};
+use crate::MAX_DEPTH;
+
+static PROFILER: EventProfiler = EventProfiler::new("pprof::contention::");
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Records an acquisition which waited for `delay`.
+#[inline(never)]
+fn record(delay: Duration) {
+    if delay.as_nanos() < THRESHOLD_NANOS.load(Ordering::Relaxed) as u128 {
+        return;
+    }
+
+    // the stack is captured before the lock is taken, so that the other
+    // acquisitions don't wait for the unwinding
+    let key = UnresolvedFrames::capture(PROFILER.max_depth());
+    if let Err(err) = PROFILER.record(&key, delay) {
+        log::warn!("failed to record a lock contention: {}", err);
+    }
+}
+
+/// Acquires a lock with `try_acquire` first, and only times the blocking
+/// `acquire` when the lock is contended.
+#[inline]
+fn acquire<G>(
+    try_acquire: impl FnOnce() -> TryLockResult<G>,
+    acquire: impl FnOnce() -> LockResult<G>,
+) -> LockResult<G> {
+    match try_acquire() {
+        Ok(guard) => return Ok(guard),
+        Err(TryLockError::Poisoned(err)) => return Err(err),
+        Err(TryLockError::WouldBlock) => {}
+    }
+
+    if !PROFILER.is_enabled() {
+        return acquire();
+    }
+
+    let start = Instant::now();
+    let result = acquire();
+    record(start.elapsed());
+    result
+}
+
+/// A `std::sync::Mutex` whose contended acquisitions are recorded by the
+/// contention profiler. It has the same interface, and returns the same guards.
+#[derive(Default)]
+pub struct Mutex<T: ?Sized> {
+    inner: std::sync::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            inner: std::sync::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        acquire(|| self.inner.try_lock(), || self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(value: T) -> Self {
+        Mutex::new(value)
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+/// A `std::sync::RwLock` whose contended acquisitions, shared or exclusive, are
+/// recorded by the contention profiler. It has the same interface, and returns
+/// the same guards.
+#[derive(Default)]
+pub struct RwLock<T: ?Sized> {
+    inner: std::sync::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            inner: std::sync::RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        acquire(|| self.inner.try_read(), || self.inner.read())
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        acquire(|| self.inner.try_write(), || self.inner.write())
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.inner.try_read()
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        self.inner.try_write()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    fn from(value: T) -> Self {
+        RwLock::new(value)
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+/// The builder of `ContentionProfilerGuard`.
+#[derive(Clone)]
+pub struct ContentionProfilerGuardBuilder {
+    threshold: Duration,
+    max_depth: usize,
+}
+
+impl Default for ContentionProfilerGuardBuilder {
+    fn default() -> ContentionProfilerGuardBuilder {
+        ContentionProfilerGuardBuilder {
+            threshold: Duration::ZERO,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+impl ContentionProfilerGuardBuilder {
+    /// Sets the minimum time an acquisition must wait to be recorded. By
+    /// default, every contended acquisition is recorded.
+    pub fn threshold(self, threshold: Duration) -> Self {
+        Self { threshold, ..self }
+    }
+
+    /// Sets the maximum number of frames recorded in a sample.
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    pub fn build(self) -> Result<ContentionProfilerGuard> {
+        PROFILER.start(self.max_depth)?;
+        THRESHOLD_NANOS.store(
+            self.threshold.as_nanos().min(u64::MAX as u128) as u64,
+            Ordering::Relaxed,
+        );
+
+        Ok(ContentionProfilerGuard {
+            threshold: self.threshold,
+        })
+    }
+}
+
+/// RAII structure used to stop the contention profiler when dropped.
+pub struct ContentionProfilerGuard {
+    threshold: Duration,
+}
+
+impl ContentionProfilerGuard {
+    /// Generates a report of the contentions recorded so far.
+    pub fn report(&self) -> Result<ContentionReport> {
+        let report = PROFILER.report()?;
+        Ok(ContentionReport {
+            data: report.data,
+            threshold: self.threshold,
+            start_time: report.start_time,
+            duration: report.duration,
+        })
+    }
+}
+
+impl Drop for ContentionProfilerGuard {
+    fn drop(&mut self) {
+        PROFILER.stop();
+    }
+}
+
+/// The contentions of a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContentionCounts {
+    /// Number of acquisitions which waited.
+    pub contentions: i64,
+    /// Total time waited, in nanoseconds.
+    pub delay: i64,
+}
+
+impl EventCounts for ContentionCounts {
+    fn new(contentions: i64, delay: i64) -> Self {
+        ContentionCounts { contentions, delay }
+    }
+
+    fn events(&self) -> i64 {
+        self.contentions
+    }
+
+    fn delay(&self) -> i64 {
+        self.delay
+    }
+}
+
+/// The report of the contention profiler.
+pub struct ContentionReport {
+    /// Key is the stack of the acquisitions and value is their counts.
+    pub data: HashMap<Frames, ContentionCounts>,
+
+    /// Minimum time waited by the recorded acquisitions.
+    pub threshold: Duration,
+
+    /// Time when the profiler was started.
+    pub start_time: SystemTime,
+
+    /// Time elapsed since the profiler was started.
+    pub duration: Duration,
+}
+
+impl Debug for ContentionReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        events::fmt(&self.data, f)
+    }
+}
+
+#[cfg(feature = "flamegraph")]
+mod flamegraph {
+    use super::*;
+    use inferno::flamegraph;
+
+    impl ContentionReport {
+        /// `flamegraph` will write an svg flamegraph of the time waited into `writer` **only
+        /// available with `flamegraph` feature**
+        pub fn flamegraph<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
+        }
+
+        /// same as `flamegraph`, but accepts custom `options` for the flamegraph
+        pub fn flamegraph_with_options<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            events::write_flamegraph(&self.data, writer, options)
+        }
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+mod protobuf {
+    use super::*;
+    use crate::protos;
+
+    const CONTENTIONS: &str = "contentions";
+    const DELAY: &str = "delay";
+    const COUNT: &str = "count";
+    const NANOSECONDS: &str = "nanoseconds";
+
+    impl ContentionReport {
+        /// `pprof` will generate google's pprof format report, with the same sample types as the
+        /// mutex profiles of Go.
+        pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            Ok(events::pprof(
+                &self.data,
+                [(CONTENTIONS, COUNT), (DELAY, NANOSECONDS)],
+                (CONTENTIONS, COUNT),
+                self.start_time,
+                self.duration,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn record_contended_lock() {
+        let guard = ContentionProfilerGuardBuilder::default().build().unwrap();
+
+        let lock = Arc::new(RwLock::new(0));
+        let held = lock.write().unwrap();
+        let waiter = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.write().unwrap() += 1)
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+        waiter.join().unwrap();
+
+        let report = guard.report().unwrap();
+        let total = events::total(&report.data);
+        assert_eq!(total.contentions, 1);
+        assert!(total.delay >= 40_000_000, "delay {}", total.delay);
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+}
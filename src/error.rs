@@ -1,17 +1,44 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::os::raw::c_int;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("{0}")]
     NixError(#[from] nix::Error),
+
     #[error("{0}")]
     IoError(#[from] std::io::Error),
+
     #[error("create profiler error")]
     CreatingError,
+
     #[error("start running cpu profiler error")]
     Running,
+
     #[error("stop running cpu profiler error")]
     NotRunning,
+
+    #[error("invalid frequency {0}, it should be between 1 and {max}", max = crate::MAX_FREQUENCY)]
+    InvalidFrequency(c_int),
+
+    #[error("invalid jitter {0}, it should be between 0 and 1")]
+    InvalidJitter(f64),
+
+    #[error("invalid max depth {0}, it should be at least 1")]
+    InvalidMaxDepth(usize),
+
+    #[error("blocklist pattern {0:?} matches no loaded library")]
+    UnmatchedBlocklist(String),
+
+    #[error("`{option}` is not supported by the {unwinder} unwinder")]
+    UnsupportedOption {
+        option: &'static str,
+        unwinder: &'static str,
+    },
+
+    #[error("fail to arm the timer: {0}")]
+    TimerError(#[source] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
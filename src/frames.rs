@@ -7,11 +7,14 @@ use std::os::raw::c_void;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
-use smallvec::SmallVec;
 use symbolic_demangle::demangle;
 
-use crate::backtrace::{Frame, Trace, TraceImpl};
-use crate::{MAX_DEPTH, MAX_THREAD_NAME};
+use crate::backtrace::{trace_current, Frame, Trace, TraceImpl, UNWIND_METHODS};
+use crate::collector::{read_raw, read_value, write_raw, write_value, Spill};
+use crate::labels::LabelSet;
+use crate::profiler::write_thread_name;
+use crate::shadow::{self, ShadowStack};
+use crate::MAX_THREAD_NAME;
 
 #[cfg(feature = "perfmaps")]
 fn resolve_in_perfmap(ip: usize) -> Option<Symbol> {
@@ -31,25 +34,39 @@ fn resolve_in_perfmap(_ip: usize) -> Option<Symbol> {
     None
 }
 
-#[derive(Clone)]
 pub struct UnresolvedFrames {
-    pub frames: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]>,
+    pub frames: Vec<<TraceImpl as Trace>::Frame>,
     pub thread_name: [u8; MAX_THREAD_NAME],
     pub thread_name_length: usize,
     pub thread_id: u64,
     pub sample_timestamp: SystemTime,
+    pub labels: LabelSet,
+    pub shadow: ShadowStack,
 }
 
 impl Default for UnresolvedFrames {
     fn default() -> Self {
-        let frames = SmallVec::with_capacity(MAX_DEPTH);
-        Self {
-            frames,
-            thread_name: [0; MAX_THREAD_NAME],
-            thread_name_length: 0,
-            thread_id: 0,
-            sample_timestamp: SystemTime::now(),
-        }
+        Self::with_max_depth(0)
+    }
+}
+
+impl Clone for UnresolvedFrames {
+    fn clone(&self) -> Self {
+        let mut frames = Self::with_max_depth(self.frames.len());
+        frames.clone_from(self);
+        frames
+    }
+
+    // Reuses the storage of `frames`, so that no allocation happens as long as
+    // it has enough capacity. The collector relies on it in the signal handler.
+    fn clone_from(&mut self, source: &Self) {
+        self.frames.clone_from(&source.frames);
+        self.thread_name = source.thread_name;
+        self.thread_name_length = source.thread_name_length;
+        self.thread_id = source.thread_id;
+        self.sample_timestamp = source.sample_timestamp;
+        self.labels = source.labels;
+        self.shadow = source.shadow;
     }
 }
 
@@ -61,7 +78,7 @@ impl Debug for UnresolvedFrames {
 
 impl UnresolvedFrames {
     pub fn new(
-        frames: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]>,
+        frames: Vec<<TraceImpl as Trace>::Frame>,
         tn: &[u8],
         thread_id: u64,
         sample_timestamp: SystemTime,
@@ -76,6 +93,127 @@ impl UnresolvedFrames {
             thread_name_length,
             thread_id,
             sample_timestamp,
+            labels: LabelSet::default(),
+            shadow: ShadowStack::default(),
+        }
+    }
+
+    /// Creates an empty `UnresolvedFrames` which can hold `max_depth` frames
+    /// without allocating.
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        Self {
+            frames: Vec::with_capacity(max_depth),
+            thread_name: [0; MAX_THREAD_NAME],
+            thread_name_length: 0,
+            thread_id: 0,
+            sample_timestamp: SystemTime::UNIX_EPOCH,
+            labels: LabelSet::default(),
+            shadow: ShadowStack::default(),
+        }
+    }
+
+    /// Captures the stack, thread and labels of the current thread, outside of
+    /// a signal handler.
+    pub(crate) fn capture(max_depth: usize) -> Self {
+        let mut sample = Self::with_max_depth(max_depth);
+        trace_current(|frame| {
+            if sample.frames.len() < max_depth {
+                sample.frames.push(frame.clone());
+                true
+            } else {
+                false
+            }
+        });
+
+        let current_thread = unsafe { libc::pthread_self() };
+        let mut name = [0; MAX_THREAD_NAME];
+        let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+
+        write_thread_name(current_thread, &mut name);
+
+        let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
+        sample.set_thread(
+            name.to_bytes(),
+            current_thread as u64,
+            SystemTime::now(),
+            crate::labels::current(),
+            shadow::current(),
+        );
+        sample
+    }
+
+    /// Sets the thread, timestamp, labels and shadow stack of this sample. The
+    /// frames are left untouched.
+    pub(crate) fn set_thread(
+        &mut self,
+        tn: &[u8],
+        thread_id: u64,
+        sample_timestamp: SystemTime,
+        labels: LabelSet,
+        shadow: ShadowStack,
+    ) {
+        self.thread_name_length = tn.len();
+        self.thread_name[0..self.thread_name_length].clone_from_slice(tn);
+        self.thread_id = thread_id;
+        self.sample_timestamp = sample_timestamp;
+        self.labels = labels;
+        self.shadow = shadow;
+    }
+}
+
+// The record is the fixed-size fields, the number of frames, and then room
+// for as many frames as the capacity of the item the collector was created
+// with, i.e. the maximum depth.
+impl Spill for UnresolvedFrames {
+    fn record_size(&self) -> usize {
+        std::mem::size_of::<[u8; MAX_THREAD_NAME]>()
+            + std::mem::size_of::<usize>() * 2
+            + std::mem::size_of::<u64>()
+            + std::mem::size_of::<SystemTime>()
+            + std::mem::size_of::<LabelSet>()
+            + std::mem::size_of::<ShadowStack>()
+            + std::mem::size_of::<<TraceImpl as Trace>::Frame>() * self.frames.capacity()
+    }
+
+    fn write_record(&self, buf: &mut [u8]) {
+        let mut offset = 0;
+        write_value(buf, &mut offset, self.thread_name);
+        write_value(buf, &mut offset, self.thread_name_length);
+        write_value(buf, &mut offset, self.thread_id);
+        write_value(buf, &mut offset, self.sample_timestamp);
+        write_value(buf, &mut offset, self.labels);
+        write_value(buf, &mut offset, self.shadow);
+
+        let room = (buf.len() - offset - std::mem::size_of::<usize>())
+            / std::mem::size_of::<<TraceImpl as Trace>::Frame>();
+        let length = std::cmp::min(self.frames.len(), room);
+        write_value(buf, &mut offset, length);
+        write_raw(buf, &mut offset, &self.frames[0..length]);
+    }
+
+    fn read_record(buf: &[u8]) -> Self {
+        let mut offset = 0;
+        let thread_name = read_value(buf, &mut offset);
+        let thread_name_length = read_value(buf, &mut offset);
+        let thread_id = read_value(buf, &mut offset);
+        let sample_timestamp = read_value(buf, &mut offset);
+        let labels = read_value(buf, &mut offset);
+        let shadow = read_value(buf, &mut offset);
+
+        let length = read_value(buf, &mut offset);
+        let mut frames = Vec::with_capacity(length);
+        // the frames were copied from a valid `Vec` by `write_record`, and they
+        // don't own any resource
+        unsafe { read_raw(buf, &mut offset, length, &mut frames) };
+
+        Self {
+            frames,
+            thread_name,
+            thread_name_length,
+            thread_id,
+            sample_timestamp,
+            labels,
+            shadow,
         }
     }
 }
@@ -83,11 +221,18 @@ impl UnresolvedFrames {
 impl PartialEq for UnresolvedFrames {
     fn eq(&self, other: &Self) -> bool {
         let (frames1, frames2) = (&self.frames, &other.frames);
-        if self.thread_id != other.thread_id || frames1.len() != frames2.len() {
+        if self.thread_id != other.thread_id
+            || self.labels != other.labels
+            || self.shadow != other.shadow
+            || frames1.len() != frames2.len()
+        {
             false
         } else {
-            Iterator::zip(frames1.iter(), frames2.iter())
-                .all(|(s1, s2)| s1.symbol_address() == s2.symbol_address())
+            // the same stack found by two methods is reported with different
+            // `UNWIND_METHODS` labels
+            Iterator::zip(frames1.iter(), frames2.iter()).all(|(s1, s2)| {
+                s1.symbol_address() == s2.symbol_address() && s1.method() == s2.method()
+            })
         }
     }
 }
@@ -96,10 +241,13 @@ impl Eq for UnresolvedFrames {}
 
 impl Hash for UnresolvedFrames {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.frames
-            .iter()
-            .for_each(|frame| frame.symbol_address().hash(state));
+        self.frames.iter().for_each(|frame| {
+            frame.symbol_address().hash(state);
+            frame.method().hash(state);
+        });
         self.thread_id.hash(state);
+        self.labels.hash(state);
+        self.shadow.hash(state);
     }
 }
 
@@ -129,11 +277,11 @@ impl Symbol {
         demangle(&String::from_utf8_lossy(self.raw_name())).into_owned()
     }
 
-    pub fn sys_name(&self) -> Cow<str> {
+    pub fn sys_name(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(self.raw_name())
     }
 
-    pub fn filename(&self) -> Cow<str> {
+    pub fn filename(&self) -> Cow<'_, str> {
         self.filename
             .as_ref()
             .map(|name| name.as_os_str().to_string_lossy())
@@ -147,6 +295,24 @@ impl Symbol {
 
 unsafe impl Send for Symbol {}
 
+impl crate::backtrace::Symbol for Symbol {
+    fn name(&self) -> Option<Vec<u8>> {
+        self.name.clone()
+    }
+
+    fn addr(&self) -> Option<*mut c_void> {
+        self.addr
+    }
+
+    fn lineno(&self) -> Option<u32> {
+        self.lineno
+    }
+
+    fn filename(&self) -> Option<PathBuf> {
+        self.filename.clone()
+    }
+}
+
 impl<T> From<&T> for Symbol
 where
     T: crate::backtrace::Symbol,
@@ -180,13 +346,15 @@ impl Hash for Symbol {
 }
 
 /// A representation of a backtrace. `thread_name` and `thread_id` was got from `pthread_getname_np`
-/// and `pthread_self`. frames is a vector of symbols.
+/// and `pthread_self`. frames is a vector of symbols. `labels` are the key-value pairs set by
+/// `with_labels` on the thread, ordered by key.
 #[derive(Clone, PartialEq, Hash)]
 pub struct Frames {
     pub frames: Vec<Vec<Symbol>>,
     pub thread_name: String,
     pub thread_id: u64,
     pub sample_timestamp: SystemTime,
+    pub labels: Vec<(String, String)>,
 }
 
 impl Frames {
@@ -198,10 +366,25 @@ impl Frames {
             format!("{:?}", self.thread_id)
         }
     }
+
+    /// Removes the frames on top of the stack, up to the last one with a symbol
+    /// matching `is_profiler`. They are the frames of the profiler, which are
+    /// the same in every sample taken outside of a signal handler.
+    pub(crate) fn strip_profiler_frames(&mut self, is_profiler: impl Fn(&str) -> bool) {
+        let last = self
+            .frames
+            .iter()
+            .rposition(|symbols| symbols.iter().any(|symbol| is_profiler(&symbol.name())));
+        if let Some(last) = last {
+            self.frames.drain(..=last);
+        }
+    }
 }
 
 impl From<UnresolvedFrames> for Frames {
     fn from(frames: UnresolvedFrames) -> Self {
+        // the symbols of the frames which are kept, with the letters of their
+        // methods
         let mut fs = Vec::new();
 
         let mut frame_iter = frames.frames.iter();
@@ -209,13 +392,10 @@ impl From<UnresolvedFrames> for Frames {
         while let Some(frame) = frame_iter.next() {
             let mut symbols: Vec<Symbol> = Vec::new();
 
-            if let Some(perfmap_symbol) = resolve_in_perfmap(frame.ip() as usize) {
+            if let Some(perfmap_symbol) = resolve_in_perfmap(frame.ip()) {
                 symbols.push(perfmap_symbol);
             } else {
-                frame.resolve_symbol(|symbol| {
-                    let symbol = Symbol::from(symbol);
-                    symbols.push(symbol);
-                });
+                frame.resolve_symbol(|symbol| symbols.push(symbol.clone()));
             }
 
             if symbols.iter().any(|symbol| {
@@ -231,16 +411,41 @@ impl From<UnresolvedFrames> for Frames {
             }
 
             if !symbols.is_empty() {
-                fs.push(symbols);
+                fs.push((symbols, frame.method().map(|method| method.letter())));
             }
         }
 
+        if !frames.shadow.is_empty() {
+            // the virtual frames only have a letter if the native ones have one
+            let method = fs
+                .iter()
+                .any(|(_, method)| method.is_some())
+                .then_some(shadow::METHOD_LETTER);
+            fs = shadow::resolve(|registry| {
+                frames.shadow.splice(
+                    fs,
+                    |(symbols, _)| registry.is_interpreter(symbols),
+                    |id| (vec![registry.symbol(id)], method),
+                )
+            });
+        }
+
+        let (fs, methods): (Vec<_>, Vec<_>) = fs.into_iter().unzip();
+        let methods: String = methods.into_iter().flatten().collect();
+
+        let mut labels = frames.labels.resolve();
+        if !methods.is_empty() {
+            labels.push((UNWIND_METHODS.to_owned(), methods));
+            labels.sort();
+        }
+
         Self {
             frames: fs,
             thread_name: String::from_utf8_lossy(&frames.thread_name[0..frames.thread_name_length])
                 .into_owned(),
             thread_id: frames.thread_id,
             sample_timestamp: frames.sample_timestamp,
+            labels,
         }
     }
 }
@@ -255,6 +460,9 @@ impl Debug for Frames {
                 write!(f, "{} -> ", symbol)?;
             }
         }
+        for (key, value) in self.labels.iter() {
+            write!(f, "LABEL: {}={} -> ", key, value)?;
+        }
         write!(f, "THREAD: ")?;
         if !self.thread_name.is_empty() {
             write!(f, "{}", self.thread_name)
@@ -267,6 +475,55 @@ impl Debug for Frames {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::collector::Collector;
+
+    fn sample_with_depth(max_depth: usize) -> UnresolvedFrames {
+        // only `backtrace-rs` can unwind without a signal context, so the
+        // frames may be empty with other unwinders
+        let mut frames = Vec::new();
+        TraceImpl::trace(std::ptr::null_mut(), |frame| {
+            frames.push(frame.clone());
+            frames.len() < max_depth
+        });
+
+        UnresolvedFrames::new(frames, b"test", 0, SystemTime::now())
+    }
+
+    #[test]
+    fn clone_from_reuses_storage() {
+        let sample = sample_with_depth(4);
+
+        let mut slot = UnresolvedFrames::with_max_depth(4);
+        let storage = slot.frames.as_ptr();
+        slot.clone_from(&sample);
+
+        assert_eq!(slot.frames.as_ptr(), storage);
+        assert!(slot == sample);
+    }
+
+    #[test]
+    fn spill_unresolved_frames() {
+        let mut sample = sample_with_depth(4);
+        let mut collector = Collector::new_with(|| UnresolvedFrames::with_max_depth(4)).unwrap();
+
+        // every thread id is a new key, so most of them are evicted into the
+        // temporary file
+        for thread_id in 0..1 << 16 {
+            sample.thread_id = thread_id;
+            collector.add(&sample, 1).unwrap();
+        }
+
+        let mut thread_ids = Vec::new();
+        for entry in collector.try_iter().unwrap() {
+            assert_eq!(entry.count, 1);
+            assert_eq!(&entry.item.thread_name[0..4], b"test");
+            sample.thread_id = entry.item.thread_id;
+            assert!(entry.item == sample);
+            thread_ids.push(entry.item.thread_id);
+        }
+        thread_ids.sort();
+        assert_eq!(thread_ids, (0..1 << 16).collect::<Vec<_>>());
+    }
 
     #[test]
     fn demangle_rust() {
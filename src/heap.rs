@@ -0,0 +1,650 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A sampling heap profiler.
+//!
+//! [`ProfiledAllocator`] wraps the global allocator. While a
+//! [`HeapProfilerGuard`] is alive, it samples an allocation every
+//! `sample_interval` bytes on average, records its stack, and tracks it until
+//! it's freed. The counts of the report are scaled up to estimate all the
+//! allocations, like in the heap profiles of Go and gperftools.
+//!
+//! ```no_run
+//! use pprof::heap::{HeapProfilerGuardBuilder, ProfiledAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOC: ProfiledAllocator<std::alloc::System> = ProfiledAllocator::new(std::alloc::System);
+//!
+//! let guard = HeapProfilerGuardBuilder::default().build().unwrap();
+//! let data = vec![0u8; 1 << 20];
+//! let report = guard.report().unwrap();
+//! println!("{:?}", report);
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backtrace::{trace_current, Trace, TraceImpl};
+use crate::error::{Error, Result};
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::MAX_DEPTH;
+
+/// The default average number of bytes between two samples, the same as Go.
+pub const DEFAULT_SAMPLE_INTERVAL: usize = 512 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static SAMPLE_INTERVAL: AtomicUsize = AtomicUsize::new(DEFAULT_SAMPLE_INTERVAL);
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+// The number of sampled allocations which haven't been freed, so that `free`
+// doesn't take the lock when there is none.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+// A counting filter of the addresses of the live sampled allocations. Most
+// frees find a zero in it, and don't take the lock.
+const FILTER_BITS: u32 = 16;
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU32 = AtomicU32::new(0);
+static FILTER: [AtomicU32; 1 << FILTER_BITS] = [ZERO; 1 << FILTER_BITS];
+
+// The state is locked in the allocator, which must not panic, even when
+// another thread panicked while holding it.
+fn lock() -> MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn filter_slot(ptr: usize) -> &'static AtomicU32 {
+    let hash = (ptr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - FILTER_BITS);
+    &FILTER[hash as usize]
+}
+
+#[derive(Clone, Copy)]
+struct Sampler {
+    // bytes to allocate until the next sample, or `None` before the first
+    // allocation of the thread
+    remaining: Option<i64>,
+    rng: u64,
+    // the thread is in the profiler, and its allocations are not sampled
+    busy: bool,
+}
+
+thread_local! {
+    // It has a const initializer and no destructor, so it can be used in the
+    // allocator without allocating.
+    static SAMPLER: Cell<Sampler> = const {
+        Cell::new(Sampler {
+            remaining: None,
+            rng: 0,
+            busy: false,
+        })
+    };
+}
+
+impl Sampler {
+    fn next_random(&mut self) -> u64 {
+        static SEED: AtomicU64 = AtomicU64::new(0);
+        if self.rng == 0 {
+            self.rng = SEED.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+                ^ (self as *const Sampler as u64);
+        }
+
+        // splitmix64
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Draws the distance to the next sample from an exponential distribution,
+    /// which makes the samples a Poisson process over the allocated bytes.
+    fn next_interval(&mut self, mean: usize) -> i64 {
+        // uniform in (0, 1]
+        let uniform = ((self.next_random() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        (-uniform.ln() * mean as f64) as i64 + 1
+    }
+}
+
+/// Runs `f` with the sampling of the current thread disabled, so that the
+/// allocations of the profiler itself are not recorded.
+fn untracked<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = SAMPLER.try_with(|sampler| {
+                sampler.set(Sampler {
+                    busy: self.0,
+                    ..sampler.get()
+                })
+            });
+        }
+    }
+
+    let previous = SAMPLER
+        .try_with(|sampler| {
+            let state = sampler.get();
+            sampler.set(Sampler {
+                busy: true,
+                ..state
+            });
+            state.busy
+        })
+        .unwrap_or(true);
+    let _restore = Restore(previous);
+    f()
+}
+
+/// The estimated counts of the allocations of a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapCounts {
+    /// Number of objects allocated since the profiler started.
+    pub alloc_objects: i64,
+    /// Bytes allocated since the profiler started.
+    pub alloc_space: i64,
+    /// Number of objects allocated and not freed yet.
+    pub inuse_objects: i64,
+    /// Bytes allocated and not freed yet.
+    pub inuse_space: i64,
+}
+
+struct Live {
+    stack: usize,
+    objects: i64,
+    space: i64,
+}
+
+struct State {
+    max_depth: usize,
+    start_time: SystemTime,
+    start_instant: Instant,
+    stacks: HashMap<UnresolvedFrames, usize>,
+    counts: Vec<HeapCounts>,
+    live: HashMap<usize, Live>,
+}
+
+impl State {
+    fn record(&mut self, ptr: usize, stack: UnresolvedFrames, objects: i64, space: i64) {
+        let next = self.counts.len();
+        let index = *self.stacks.entry(stack).or_insert(next);
+        if index == next {
+            self.counts.push(HeapCounts::default());
+        }
+
+        let counts = &mut self.counts[index];
+        counts.alloc_objects += objects;
+        counts.alloc_space += space;
+        counts.inuse_objects += objects;
+        counts.inuse_space += space;
+
+        let live = Live {
+            stack: index,
+            objects,
+            space,
+        };
+        if let Some(previous) = self.live.insert(ptr, live) {
+            // the allocator reused the address of an allocation whose free
+            // was missed, e.g. the one of a failed `realloc`
+            self.remove(previous);
+        } else {
+            filter_slot(ptr).fetch_add(1, Ordering::SeqCst);
+            LIVE.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn remove(&mut self, live: Live) {
+        let counts = &mut self.counts[live.stack];
+        counts.inuse_objects -= live.objects;
+        counts.inuse_space -= live.space;
+    }
+
+    fn free(&mut self, ptr: usize) {
+        if let Some(live) = self.live.remove(&ptr) {
+            filter_slot(ptr).fetch_sub(1, Ordering::SeqCst);
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+            self.remove(live);
+        }
+    }
+
+    fn clear(&mut self) {
+        for ptr in self.live.keys() {
+            filter_slot(*ptr).fetch_sub(1, Ordering::SeqCst);
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.live.clear();
+    }
+}
+
+#[inline]
+fn on_alloc(ptr: *mut u8, size: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let sampled = SAMPLER
+        .try_with(|cell| {
+            let mut sampler = cell.get();
+            if sampler.busy {
+                return false;
+            }
+
+            let interval = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+            let remaining = match sampler.remaining {
+                Some(remaining) => remaining - size as i64,
+                None => sampler.next_interval(interval) - size as i64,
+            };
+            if remaining > 0 {
+                sampler.remaining = Some(remaining);
+                cell.set(sampler);
+                return false;
+            }
+
+            sampler.remaining = Some(sampler.next_interval(interval));
+            cell.set(sampler);
+            true
+        })
+        .unwrap_or(false);
+
+    if sampled {
+        untracked(|| sample(ptr as usize, size));
+    }
+}
+
+#[inline(never)]
+fn sample(ptr: usize, size: usize) {
+    let interval = SAMPLE_INTERVAL.load(Ordering::Relaxed) as f64;
+    // the probability of an allocation of `size` bytes to be sampled
+    let probability = 1.0 - (-(size as f64) / interval).exp();
+    let objects = (1.0 / probability).round() as i64;
+    let space = (size as f64 / probability).round() as i64;
+
+    let max_depth = match lock().as_ref() {
+        Some(state) => state.max_depth,
+        None => return,
+    };
+
+    let mut stack = UnresolvedFrames::with_max_depth(max_depth);
+    trace_current(|frame| {
+        if stack.frames.len() < max_depth {
+            stack.frames.push(frame.clone());
+            true
+        } else {
+            false
+        }
+    });
+
+    if let Some(state) = lock().as_mut() {
+        state.record(ptr, stack, objects, space);
+    }
+}
+
+#[inline]
+fn on_free(ptr: *mut u8) {
+    if LIVE.load(Ordering::Relaxed) == 0 || filter_slot(ptr as usize).load(Ordering::SeqCst) == 0 {
+        return;
+    }
+
+    // The profiler doesn't free the allocations it samples, so a busy thread
+    // only hits a false positive of the filter, and it must not take the lock
+    // it may already hold.
+    let busy = SAMPLER
+        .try_with(|sampler| sampler.get().busy)
+        .unwrap_or(true);
+    if !busy {
+        untracked(|| {
+            if let Some(state) = lock().as_mut() {
+                state.free(ptr as usize);
+            }
+        });
+    }
+}
+
+/// A `GlobalAlloc` which samples the allocations of `A` for the heap profiler.
+/// It costs a few atomic loads per allocation when the profiler is not
+/// running.
+pub struct ProfiledAllocator<A> {
+    inner: A,
+}
+
+impl<A> ProfiledAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        ProfiledAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ProfiledAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            on_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            on_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // It's forgotten before it's freed, as the address can be reused by
+        // another thread right after.
+        on_free(ptr);
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        on_free(ptr);
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            on_alloc(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+/// The builder of `HeapProfilerGuard`.
+#[derive(Clone)]
+pub struct HeapProfilerGuardBuilder {
+    sample_interval: usize,
+    max_depth: usize,
+}
+
+impl Default for HeapProfilerGuardBuilder {
+    fn default() -> HeapProfilerGuardBuilder {
+        HeapProfilerGuardBuilder {
+            sample_interval: DEFAULT_SAMPLE_INTERVAL,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+impl HeapProfilerGuardBuilder {
+    /// Sets the average number of bytes allocated between two samples. A
+    /// smaller interval gives a more precise profile, at a higher cost.
+    pub fn sample_interval(self, sample_interval: usize) -> Self {
+        Self {
+            sample_interval,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of frames recorded in a sample.
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    pub fn build(self) -> Result<HeapProfilerGuard> {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            log::warn!("ProfiledAllocator is not the global allocator, no allocation is sampled");
+        }
+
+        untracked(|| {
+            // the unwinder may allocate when it's first used
+            TraceImpl::init();
+
+            let mut state = lock();
+            if state.is_some() {
+                return Err(Error::Running);
+            }
+
+            *state = Some(State {
+                max_depth: self.max_depth,
+                start_time: SystemTime::now(),
+                start_instant: Instant::now(),
+                stacks: HashMap::new(),
+                counts: Vec::new(),
+                live: HashMap::new(),
+            });
+            SAMPLE_INTERVAL.store(self.sample_interval.max(1), Ordering::Relaxed);
+            ENABLED.store(true, Ordering::SeqCst);
+
+            Ok(HeapProfilerGuard { _private: () })
+        })
+    }
+}
+
+/// RAII structure used to stop the heap profiler when dropped.
+pub struct HeapProfilerGuard {
+    _private: (),
+}
+
+impl HeapProfilerGuard {
+    /// Generates a report of the allocations sampled so far.
+    pub fn report(&self) -> Result<HeapReport> {
+        let (stacks, sample_interval, start_time, duration) = untracked(|| {
+            let state = lock();
+            let state = state.as_ref().ok_or(Error::NotRunning)?;
+
+            let stacks: Vec<_> = state
+                .stacks
+                .iter()
+                .map(|(stack, index)| (stack.clone(), state.counts[*index]))
+                .collect();
+            Ok::<_, Error>((
+                stacks,
+                SAMPLE_INTERVAL.load(Ordering::Relaxed),
+                state.start_time,
+                state.start_instant.elapsed(),
+            ))
+        })?;
+
+        let mut data: HashMap<Frames, HeapCounts> = HashMap::new();
+        for (stack, counts) in stacks {
+            let mut frames = Frames::from(stack);
+            // the frames of the profiler, of the allocator wrapper and of the
+            // allocator shim of the compiler
+            frames.strip_profiler_frames(|name| {
+                name.contains("pprof::heap::")
+                    || name.contains("__rust_alloc")
+                    || name.contains("__rust_realloc")
+            });
+
+            let entry = data.entry(frames).or_default();
+            entry.alloc_objects += counts.alloc_objects;
+            entry.alloc_space += counts.alloc_space;
+            entry.inuse_objects += counts.inuse_objects;
+            entry.inuse_space += counts.inuse_space;
+        }
+
+        Ok(HeapReport {
+            data,
+            sample_interval,
+            start_time,
+            duration,
+        })
+    }
+}
+
+impl Drop for HeapProfilerGuard {
+    fn drop(&mut self) {
+        ENABLED.store(false, Ordering::SeqCst);
+        untracked(|| {
+            let mut state = lock();
+            if let Some(state) = state.as_mut() {
+                state.clear();
+            }
+            *state = None;
+        });
+    }
+}
+
+/// The report of the heap profiler. The counts are estimates of all the
+/// allocations, computed from the sampled ones.
+pub struct HeapReport {
+    /// Key is the stack of the allocations and value is their counts.
+    pub data: HashMap<Frames, HeapCounts>,
+
+    /// Average number of bytes between two samples.
+    pub sample_interval: usize,
+
+    /// Time when the profiler was started.
+    pub start_time: SystemTime,
+
+    /// Time elapsed since the profiler was started.
+    pub duration: Duration,
+}
+
+impl Debug for HeapReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for (key, val) in self.data.iter() {
+            write!(f, "{:?} {:?}", key, val)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "flamegraph")]
+mod flamegraph {
+    use super::*;
+    use crate::report::flamegraph::write_flamegraph;
+    use inferno::flamegraph;
+
+    impl HeapReport {
+        /// `flamegraph` will write an svg flamegraph of the bytes in use into `writer` **only
+        /// available with `flamegraph` feature**
+        pub fn flamegraph<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
+        }
+
+        /// same as `flamegraph`, but accepts custom `options` for the flamegraph
+        pub fn flamegraph_with_options<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            let samples = self
+                .data
+                .iter()
+                .filter(|(_, counts)| counts.inuse_space > 0)
+                .map(|(key, counts)| (key, counts.inuse_space));
+            write_flamegraph(writer, options, samples, &[], false)
+        }
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+mod protobuf {
+    use super::*;
+    use crate::protos;
+    use crate::report::protobuf::ProfileBuilder;
+
+    const ALLOC_OBJECTS: &str = "alloc_objects";
+    const ALLOC_SPACE: &str = "alloc_space";
+    const INUSE_OBJECTS: &str = "inuse_objects";
+    const INUSE_SPACE: &str = "inuse_space";
+    const COUNT: &str = "count";
+    const BYTES: &str = "bytes";
+    const SPACE: &str = "space";
+
+    impl HeapReport {
+        /// `pprof` will generate google's pprof format report, with the same sample types as the
+        /// heap profiles of Go.
+        pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            let mut builder = ProfileBuilder::new();
+            for (key, counts) in self.data.iter() {
+                let values = vec![
+                    counts.alloc_objects,
+                    counts.alloc_space,
+                    counts.inuse_objects,
+                    counts.inuse_space,
+                ];
+                builder.add_sample(key, values, &[]);
+            }
+
+            Ok(builder.build(
+                &[
+                    (ALLOC_OBJECTS, COUNT),
+                    (ALLOC_SPACE, BYTES),
+                    (INUSE_OBJECTS, COUNT),
+                    (INUSE_SPACE, BYTES),
+                ],
+                (SPACE, BYTES),
+                self.sample_interval as i64,
+                self.start_time,
+                self.duration,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_intervals() {
+        let mut sampler = Sampler {
+            remaining: None,
+            rng: 0,
+            busy: false,
+        };
+
+        let count = 100_000;
+        let total: i64 = (0..count).map(|_| sampler.next_interval(1024)).sum();
+        let mean = total / count;
+        assert!((980..1070).contains(&mean), "mean interval {}", mean);
+    }
+
+    #[test]
+    fn track_allocations() {
+        // it isn't the global allocator of the tests, so it's called directly
+        let allocator = ProfiledAllocator::new(std::alloc::System);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let guard = HeapProfilerGuardBuilder::default()
+            .sample_interval(1)
+            .build()
+            .unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+
+        let counts = |report: HeapReport| {
+            report
+                .data
+                .values()
+                .fold(HeapCounts::default(), |mut total, counts| {
+                    total.alloc_objects += counts.alloc_objects;
+                    total.alloc_space += counts.alloc_space;
+                    total.inuse_objects += counts.inuse_objects;
+                    total.inuse_space += counts.inuse_space;
+                    total
+                })
+        };
+
+        let expected = HeapCounts {
+            alloc_objects: 1,
+            alloc_space: 4096,
+            inuse_objects: 1,
+            inuse_space: 4096,
+        };
+        assert_eq!(counts(guard.report().unwrap()), expected);
+
+        unsafe { allocator.dealloc(ptr, layout) };
+        let expected = HeapCounts {
+            inuse_objects: 0,
+            inuse_space: 0,
+            ..expected
+        };
+        assert_eq!(counts(guard.report().unwrap()), expected);
+
+        drop(guard);
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+    }
+}
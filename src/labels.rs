@@ -0,0 +1,209 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+/// The maximum number of labels on a thread at the same time. The labels
+/// added beyond it are ignored.
+pub const MAX_LABELS: usize = 8;
+
+/// The labels of a sample, as the ids of the interned key-value pairs. It's
+/// `Copy`, so that the signal handler can take it without allocating.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LabelSet {
+    ids: [u32; MAX_LABELS],
+    len: usize,
+
+    #[cfg(feature = "tokio")]
+    task: crate::tokio::Task,
+}
+
+impl LabelSet {
+    const EMPTY: LabelSet = LabelSet {
+        ids: [0; MAX_LABELS],
+        len: 0,
+
+        #[cfg(feature = "tokio")]
+        task: crate::tokio::Task::NONE,
+    };
+
+    fn ids(&self) -> &[u32] {
+        &self.ids[..self.len]
+    }
+
+    /// Returns the key-value pairs of the labels, ordered by key.
+    pub fn resolve(&self) -> Vec<(String, String)> {
+        let mut labels = resolve(|registry| {
+            #[allow(unused_mut)]
+            let mut labels: Vec<_> = self
+                .ids()
+                .iter()
+                .map(|id| registry[*id as usize].clone())
+                .collect();
+
+            #[cfg(feature = "tokio")]
+            labels.extend(self.task.labels(registry));
+
+            labels
+        });
+        labels.sort();
+        labels
+    }
+}
+
+/// Every key-value pair which has been used as a label gets an id, which is
+/// never released. Like in Go, the labels should have a low cardinality.
+#[derive(Default)]
+struct Registry {
+    ids: HashMap<(String, String), u32>,
+    labels: Vec<(String, String)>,
+}
+
+impl Registry {
+    fn intern(&mut self, key: &str, value: &str) -> u32 {
+        let label = (key.to_owned(), value.to_owned());
+        if let Some(id) = self.ids.get(&label) {
+            return *id;
+        }
+
+        let id = self.labels.len() as u32;
+        self.labels.push(label.clone());
+        self.ids.insert(label, id);
+        id
+    }
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Default::default);
+
+/// Returns the id of a key-value pair.
+#[cfg(feature = "tokio")]
+pub(crate) fn intern(key: &str, value: &str) -> u32 {
+    REGISTRY.lock().unwrap().intern(key, value)
+}
+
+/// Calls `f` with the key-value pairs, indexed by their ids.
+pub(crate) fn resolve<T>(f: impl FnOnce(&[(String, String)]) -> T) -> T {
+    f(&REGISTRY.lock().unwrap().labels)
+}
+
+thread_local! {
+    // It has a const initializer and no destructor, so reading it doesn't
+    // allocate or register anything, which makes it usable in the signal
+    // handler.
+    static LABELS: Cell<LabelSet> = const { Cell::new(LabelSet::EMPTY) };
+}
+
+/// Returns the labels of the current thread. It's called in the signal
+/// handler.
+pub(crate) fn current() -> LabelSet {
+    #[allow(unused_mut)]
+    let mut labels = LABELS.try_with(Cell::get).unwrap_or_default();
+
+    #[cfg(feature = "tokio")]
+    {
+        labels.task = crate::tokio::current();
+    }
+
+    labels
+}
+
+/// Restores the previous labels when `with_labels` returns or unwinds.
+struct Restore(LabelSet);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        LABELS.with(|labels| labels.set(self.0));
+    }
+}
+
+/// Runs `f` with `labels` added to the labels of the current thread. Every
+/// sample taken on this thread while `f` runs carries them, and `pprof()`
+/// emits them as labels of the sample. A label replaces an outer one with
+/// the same key, and the previous labels are restored once `f` returns.
+///
+/// ```
+/// let value = pprof::with_labels(&[("endpoint", "/get")], || 1 + 1);
+/// assert_eq!(value, 2);
+/// ```
+pub fn with_labels<T, F: FnOnce() -> T>(labels: &[(&str, &str)], f: F) -> T {
+    let previous = LABELS.with(Cell::get);
+
+    let mut set = previous;
+    {
+        let mut registry = REGISTRY.lock().unwrap();
+        for (key, value) in labels {
+            let id = registry.intern(key, value);
+
+            let mut ids: Vec<u32> = set
+                .ids()
+                .iter()
+                .copied()
+                .filter(|id| registry.labels[*id as usize].0 != *key)
+                .collect();
+            if ids.len() == MAX_LABELS {
+                log::warn!("too many profiling labels, {}={} is ignored", key, value);
+                continue;
+            }
+            ids.push(id);
+            // the same labels always give the same set
+            ids.sort_unstable();
+
+            set.ids[..ids.len()].copy_from_slice(&ids);
+            set.ids[ids.len()..].fill(0);
+            set.len = ids.len();
+        }
+    }
+
+    let _restore = Restore(previous);
+    LABELS.with(|labels| labels.set(set));
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_labels() {
+        with_labels(&[("endpoint", "/get"), ("region", "1")], || {
+            with_labels(&[("endpoint", "/put")], || {
+                assert_eq!(
+                    current().resolve(),
+                    vec![
+                        ("endpoint".to_owned(), "/put".to_owned()),
+                        ("region".to_owned(), "1".to_owned()),
+                    ]
+                );
+            });
+
+            assert_eq!(
+                current().resolve(),
+                vec![
+                    ("endpoint".to_owned(), "/get".to_owned()),
+                    ("region".to_owned(), "1".to_owned()),
+                ]
+            );
+        });
+
+        assert_eq!(current(), LabelSet::default());
+    }
+
+    #[test]
+    fn same_labels_same_set() {
+        let first = with_labels(&[("a", "1"), ("b", "2")], current);
+        let second = with_labels(&[("b", "2")], || with_labels(&[("a", "1")], current));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn labels_are_restored_on_panic() {
+        let result = std::panic::catch_unwind(|| {
+            with_labels(&[("a", "1")], || panic!("expected"));
+        });
+        assert!(result.is_err());
+        assert_eq!(current(), LabelSet::default());
+    }
+}
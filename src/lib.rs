@@ -24,52 +24,118 @@
 //! More configuration can be passed through `ProfilerGuardBuilder`:
 //!
 //! ```rust
-//! let guard = pprof::ProfilerGuardBuilder::default().frequency(1000).blocklist(&["libc", "libgcc", "pthread", "vdso"]).build().unwrap();
+//! let guard = pprof::ProfilerGuardBuilder::default().frequency(1000).blocklist(&["libc", "libgcc", "vdso"]).build().unwrap();
 //! ```
 //!
 //! The frequency means the sampler frequency, and the `blocklist` means the
 //! profiler will ignore the sample whose first frame is from library containing
 //! these strings.
 //!
-//! Skipping `libc`, `libgcc` and `libpthread` could be a solution to the
-//! possible deadlock inside the `_Unwind_Backtrace`, and keep the signal
-//! safety. The dwarf information in "vdso" is incorrect in some distributions,
-//! so it's also suggested to skip it.
+//! Skipping `libc` and `libgcc` could be a solution to the possible deadlock
+//! inside the `_Unwind_Backtrace`, and keep the signal safety. `libpthread` is
+//! merged into `libc` since glibc 2.34, and `build` fails if a pattern matches
+//! no loaded library, so it's only given where it's still a separate library.
+//! The dwarf information in "vdso" is incorrect in some distributions, so it's
+//! also suggested to skip it.
 //!
 //! You can find more details in
 //! [README.md](https://github.com/tikv/pprof-rs/blob/master/README.md)
 
-/// Define the MAX supported stack depth. TODO: make this variable mutable.
-#[cfg(feature = "large-depth")]
-pub const MAX_DEPTH: usize = 1024;
-
-#[cfg(all(feature = "huge-depth", not(feature = "large-depth")))]
-pub const MAX_DEPTH: usize = 512;
-
-#[cfg(not(any(feature = "large-depth", feature = "huge-depth")))]
+/// Define the default max stack depth. It can be changed at runtime through
+/// `ProfilerGuardBuilder::max_depth`.
 pub const MAX_DEPTH: usize = 128;
 
+/// The highest sampling frequency, whose interval is a microsecond, the resolution of
+/// `setitimer`.
+pub const MAX_FREQUENCY: std::os::raw::c_int = 1_000_000;
+
 /// Define the MAX supported thread name length. TODO: make this variable mutable.
 pub const MAX_THREAD_NAME: usize = 16;
 
 mod addr_validate;
 
 mod backtrace;
+mod blocklist;
+mod buffer;
 mod collector;
+pub mod contention;
 mod error;
 mod frames;
+pub mod heap;
+mod labels;
+mod modules;
+pub mod offcpu;
 #[cfg(feature = "perfmaps")]
 mod perfmap;
 mod profiler;
 mod report;
+pub mod shadow;
+mod stats;
 mod timer;
 
-pub use self::addr_validate::validate;
-pub use self::collector::{Collector, HashCounter};
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+/// The traits of the unwinders and of their frames and symbols, to plug in a
+/// custom unwinder with `ProfilerGuardBuilder::custom_unwinder`.
+///
+/// ```
+/// use pprof::unwind::{Frame, Trace};
+///
+/// #[derive(Clone, Copy)]
+/// struct GreenFrame {
+///     ip: usize,
+/// }
+///
+/// impl Frame for GreenFrame {
+///     type S = pprof::Symbol;
+///
+///     fn resolve_symbol<F: FnMut(&Self::S)>(&self, mut cb: F) {
+///         cb(&pprof::Symbol {
+///             name: Some(b"green_thread".to_vec()),
+///             addr: None,
+///             lineno: None,
+///             filename: None,
+///         })
+///     }
+///
+///     fn symbol_address(&self) -> *mut libc::c_void {
+///         self.ip as *mut libc::c_void
+///     }
+///
+///     fn ip(&self) -> usize {
+///         self.ip
+///     }
+/// }
+///
+/// struct GreenTrace;
+///
+/// impl Trace for GreenTrace {
+///     type Frame = GreenFrame;
+///
+///     fn trace<F: FnMut(&Self::Frame) -> bool>(_: *mut libc::c_void, mut cb: F) {
+///         // walk the stack of the green thread running on this thread
+///         cb(&GreenFrame { ip: 0x1000 });
+///     }
+/// }
+///
+/// let builder = pprof::ProfilerGuardBuilder::default().custom_unwinder::<GreenTrace>();
+/// ```
+pub mod unwind {
+    pub use crate::backtrace::{Frame, Symbol, Trace, UnwindMethod, CUSTOM_FRAME_WORDS};
+}
+
+pub use self::addr_validate::{validate, Validator};
+pub use self::backtrace::{register_thread, Unwinder, UNWIND_METHODS};
+pub use self::blocklist::BlocklistAction;
+pub use self::collector::{Collector, HashCounter, Spill};
 pub use self::error::{Error, Result};
 pub use self::frames::{Frames, Symbol};
+pub use self::labels::{with_labels, LabelSet, MAX_LABELS};
 pub use self::profiler::{ProfilerGuard, ProfilerGuardBuilder};
 pub use self::report::{Report, ReportBuilder, UnresolvedReport};
+pub use self::stats::SampleStats;
+pub use self::timer::TimerBackend;
 
 #[cfg(feature = "flamegraph")]
 pub use inferno::flamegraph;
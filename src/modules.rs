@@ -0,0 +1,153 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
+use spin::RwLock;
+
+// The address ranges of the segments of the loaded modules, sorted, and
+// whether they're executable.
+static SEGMENTS: RwLock<Vec<(usize, usize, bool)>> = RwLock::new(Vec::new());
+
+/// Returns a number which changes whenever a library is loaded or unloaded,
+/// e.g. with `dlopen`. It must not be called in the signal handler, as the
+/// dynamic loader takes a lock.
+pub fn generation() -> u64 {
+    #[cfg(target_os = "linux")]
+    if let Some(generation) = loader_generation() {
+        return generation;
+    }
+
+    // The load addresses of the libraries tell most changes apart, except
+    // a library which is reloaded at the same address.
+    let mut hasher = DefaultHasher::new();
+    TargetSharedLibrary::each(|shlib| {
+        shlib.name().hash(&mut hasher);
+        shlib.actual_load_addr().0.hash(&mut hasher);
+    });
+    hasher.finish()
+}
+
+/// Takes a new list of the segments of the loaded modules, which `is_code` and
+/// `is_loaded` look up. It must not be called in the signal handler.
+#[allow(dead_code)]
+pub fn refresh_segments() {
+    let mut segments = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        for segment in shlib.segments().filter(|segment| segment.is_load()) {
+            let start = segment.actual_virtual_memory_address(shlib).0;
+            segments.push((start, start + segment.len(), segment.is_code()));
+        }
+    });
+    segments.sort_unstable();
+
+    // the previous list is freed after the lock is released
+    let _previous = std::mem::replace(&mut *SEGMENTS.write(), segments);
+}
+
+/// Tells whether the `len` bytes at `addr` are in a segment of a loaded module
+/// which is executable, if `code` is set. It can be called in the signal
+/// handler, and returns `false` while the list is being refreshed.
+fn in_segment(addr: usize, len: usize, code: bool) -> bool {
+    let segments = match SEGMENTS.try_read() {
+        Some(segments) => segments,
+        None => return false,
+    };
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    // the last segment which starts at or before `addr`
+    let index = segments.partition_point(|segment| segment.0 <= addr);
+    index > 0 && {
+        let segment = segments[index - 1];
+        end <= segment.1 && (segment.2 || !code)
+    }
+}
+
+/// Tells whether the `len` bytes at `addr` are in an executable segment, so
+/// that the instructions there can be read without checking the address with
+/// a syscall.
+#[allow(dead_code)]
+pub fn is_code(addr: usize, len: usize) -> bool {
+    in_segment(addr, len, true)
+}
+
+/// Tells whether the `len` bytes at `addr` are in a segment of a loaded
+/// module, e.g. its global offset table.
+#[allow(dead_code)]
+pub fn is_loaded(addr: usize, len: usize) -> bool {
+    in_segment(addr, len, false)
+}
+
+/// Returns the number of libraries loaded and unloaded by the dynamic loader,
+/// which is only given by glibc and musl.
+#[cfg(target_os = "linux")]
+fn loader_generation() -> Option<u64> {
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        size: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        // the counters are the same in every entry, and only present if the
+        // structure given by the loader is as large as the one of `libc`
+        if size >= std::mem::size_of::<libc::dl_phdr_info>() {
+            let generation = (*info).dlpi_adds.wrapping_add((*info).dlpi_subs);
+            *(data as *mut Option<u64>) = Some(generation);
+        }
+        1
+    }
+
+    let mut generation: Option<u64> = None;
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(callback),
+            &mut generation as *mut Option<u64> as *mut libc::c_void,
+        )
+    };
+    generation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_is_stable() {
+        assert_eq!(generation(), generation());
+    }
+
+    #[test]
+    fn segments_of_loaded_modules() {
+        static DATA: [u8; 16] = [0; 16];
+
+        refresh_segments();
+        let code = segments_of_loaded_modules as fn() as usize;
+        assert!(is_code(code, 4));
+        assert!(is_loaded(code, 4));
+        assert!(is_loaded(DATA.as_ptr() as usize, DATA.len()));
+        assert!(!is_code(DATA.as_ptr() as usize, DATA.len()));
+
+        let heap = Box::new(0u64);
+        assert!(!is_loaded(&*heap as *const u64 as usize, 8));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn generation_changes_on_dlopen() {
+        let name = b"libm.so.6\0".as_ptr() as *const libc::c_char;
+        // it must not be loaded yet
+        if !unsafe { libc::dlopen(name, libc::RTLD_NOW | libc::RTLD_NOLOAD) }.is_null() {
+            return;
+        }
+
+        let before = generation();
+        let handle = unsafe { libc::dlopen(name, libc::RTLD_NOW) };
+        if handle.is_null() {
+            return;
+        }
+        assert_ne!(generation(), before);
+    }
+}
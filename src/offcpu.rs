@@ -0,0 +1,241 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An off-CPU profiler of the instrumented blocking regions.
+//!
+//! The CPU profiler only samples the threads which are running. The regions
+//! where a thread may sleep, e.g. on I/O or a condition variable, can be
+//! wrapped in [`blocking`] or [`enter`]. While an [`OffCpuProfilerGuard`] is
+//! alive, the stack of every region is captured on entry, and weighted by the
+//! time spent in the region on exit. It can run alongside a `ProfilerGuard`.
+//!
+//! ```
+//! use pprof::offcpu::{blocking, OffCpuProfilerGuardBuilder};
+//!
+//! let guard = OffCpuProfilerGuardBuilder::default().build().unwrap();
+//! blocking(|| std::thread::sleep(std::time::Duration::from_millis(10)));
+//! let report = guard.report().unwrap();
+//! println!("{:?}", report);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::Result;
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::report::events::{self, EventCounts, EventProfiler};
+use crate::MAX_DEPTH;
+
+static PROFILER: EventProfiler = EventProfiler::new("pprof::offcpu::");
+
+/// Runs `f` as a blocking region: its time is recorded by the off-CPU
+/// profiler, with the stack of the caller.
+#[inline(never)]
+pub fn blocking<T, F: FnOnce() -> T>(f: F) -> T {
+    let _region = enter();
+    f()
+}
+
+/// Enters a blocking region, which lasts until the returned guard is dropped.
+/// It costs an atomic load when the off-CPU profiler is not running.
+#[inline(never)]
+pub fn enter() -> BlockingRegion {
+    if !PROFILER.is_enabled() {
+        return BlockingRegion { entry: None };
+    }
+
+    // the state is only locked once the region exits
+    BlockingRegion {
+        entry: Some((
+            UnresolvedFrames::capture(PROFILER.max_depth()),
+            Instant::now(),
+        )),
+    }
+}
+
+/// RAII structure of a blocking region, created by [`enter`]. The time since
+/// the entry is recorded when it's dropped.
+pub struct BlockingRegion {
+    entry: Option<(UnresolvedFrames, Instant)>,
+}
+
+impl Drop for BlockingRegion {
+    fn drop(&mut self) {
+        let (key, entered) = match self.entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Err(err) = PROFILER.record(&key, entered.elapsed()) {
+            log::warn!("failed to record a blocking region: {}", err);
+        }
+    }
+}
+
+/// The builder of `OffCpuProfilerGuard`.
+#[derive(Clone)]
+pub struct OffCpuProfilerGuardBuilder {
+    max_depth: usize,
+}
+
+impl Default for OffCpuProfilerGuardBuilder {
+    fn default() -> OffCpuProfilerGuardBuilder {
+        OffCpuProfilerGuardBuilder {
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+impl OffCpuProfilerGuardBuilder {
+    /// Sets the maximum number of frames recorded in a sample.
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth }
+    }
+
+    pub fn build(self) -> Result<OffCpuProfilerGuard> {
+        PROFILER.start(self.max_depth)?;
+
+        Ok(OffCpuProfilerGuard { _private: () })
+    }
+}
+
+/// RAII structure used to stop the off-CPU profiler when dropped.
+pub struct OffCpuProfilerGuard {
+    _private: (),
+}
+
+impl OffCpuProfilerGuard {
+    /// Generates a report of the blocking regions which have exited so far.
+    pub fn report(&self) -> Result<OffCpuReport> {
+        let report = PROFILER.report()?;
+        Ok(OffCpuReport {
+            data: report.data,
+            start_time: report.start_time,
+            duration: report.duration,
+        })
+    }
+}
+
+impl Drop for OffCpuProfilerGuard {
+    fn drop(&mut self) {
+        PROFILER.stop();
+    }
+}
+
+/// The blocking regions of a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OffCpuCounts {
+    /// Number of regions which exited.
+    pub events: i64,
+    /// Total time spent in the regions, in nanoseconds.
+    pub delay: i64,
+}
+
+impl EventCounts for OffCpuCounts {
+    fn new(events: i64, delay: i64) -> Self {
+        OffCpuCounts { events, delay }
+    }
+
+    fn events(&self) -> i64 {
+        self.events
+    }
+
+    fn delay(&self) -> i64 {
+        self.delay
+    }
+}
+
+/// The report of the off-CPU profiler.
+pub struct OffCpuReport {
+    /// Key is the stack of the blocking regions and value is their counts.
+    pub data: HashMap<Frames, OffCpuCounts>,
+
+    /// Time when the profiler was started.
+    pub start_time: SystemTime,
+
+    /// Time elapsed since the profiler was started.
+    pub duration: Duration,
+}
+
+impl Debug for OffCpuReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        events::fmt(&self.data, f)
+    }
+}
+
+#[cfg(feature = "flamegraph")]
+mod flamegraph {
+    use super::*;
+    use inferno::flamegraph;
+
+    impl OffCpuReport {
+        /// `flamegraph` will write an svg flamegraph of the time spent off-CPU into `writer`
+        /// **only available with `flamegraph` feature**
+        pub fn flamegraph<W>(&self, writer: W) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
+        }
+
+        /// same as `flamegraph`, but accepts custom `options` for the flamegraph
+        pub fn flamegraph_with_options<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            events::write_flamegraph(&self.data, writer, options)
+        }
+    }
+}
+
+#[cfg(feature = "_protobuf")]
+mod protobuf {
+    use super::*;
+    use crate::protos;
+
+    const SAMPLES: &str = "samples";
+    const OFF_CPU: &str = "off-cpu";
+    const COUNT: &str = "count";
+    const NANOSECONDS: &str = "nanoseconds";
+
+    impl OffCpuReport {
+        /// `pprof` will generate google's pprof format report, with the number of regions and
+        /// the `off-cpu` time spent in them.
+        pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            Ok(events::pprof(
+                &self.data,
+                [(SAMPLES, COUNT), (OFF_CPU, NANOSECONDS)],
+                (OFF_CPU, NANOSECONDS),
+                self.start_time,
+                self.duration,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_blocking_regions() {
+        // a region entered before the profiler started isn't recorded
+        let early = enter();
+
+        let guard = OffCpuProfilerGuardBuilder::default().build().unwrap();
+        blocking(|| std::thread::sleep(Duration::from_millis(20)));
+        {
+            let _region = enter();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        drop(early);
+
+        let report = guard.report().unwrap();
+        let total = events::total(&report.data);
+        assert_eq!(total.events, 2);
+        assert!(total.delay >= 40_000_000, "delay {}", total.delay);
+    }
+}
@@ -1,12 +1,15 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::os::raw::c_int;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
 use std::time::SystemTime;
 
-use nix::sys::signal;
 use once_cell::sync::Lazy;
-use smallvec::SmallVec;
 use spin::RwLock;
 
 #[cfg(any(
@@ -17,25 +20,81 @@ use spin::RwLock;
 ))]
 use findshlibs::{Segment, SharedLibrary, TargetSharedLibrary};
 
-use crate::backtrace::{Trace, TraceImpl};
+use crate::addr_validate::Validator;
+use crate::backtrace::{CustomUnwinder, SelectedUnwinder, Trace, TraceImpl, Unwinder};
+use crate::blocklist::{BlocklistAction, FunctionBlocklist, StackWalk};
+use crate::buffer::{Aggregator, SampleBuffers, SHARD_CAPACITY};
 use crate::collector::Collector;
 use crate::error::{Error, Result};
 use crate::frames::UnresolvedFrames;
 use crate::report::ReportBuilder;
-use crate::timer::Timer;
-use crate::{MAX_DEPTH, MAX_THREAD_NAME};
+use crate::stats::COUNTERS;
+use crate::timer::{Timer, TimerBackend};
+use crate::{MAX_DEPTH, MAX_FREQUENCY, MAX_THREAD_NAME};
 
 pub(crate) static PROFILER: Lazy<RwLock<Result<Profiler>>> =
     Lazy::new(|| RwLock::new(Profiler::new()));
 
-pub struct Profiler {
-    pub(crate) data: Collector<UnresolvedFrames>,
-    sample_counter: i32,
+// The signal handler which was installed before the profiler, and whether it
+// takes a `siginfo_t`. The signals which were not sent by the profiler are
+// forwarded to it. They are read in the signal handler, so they are kept out
+// of the lock of `PROFILER`. `PREVIOUS_HANDLER` is zero if nothing should be
+// forwarded.
+#[cfg(target_os = "linux")]
+static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(0);
+#[cfg(target_os = "linux")]
+static PREVIOUS_SIGINFO: AtomicBool = AtomicBool::new(false);
+// The `si_code` of the signals sent by the timer of the profiler.
+#[cfg(target_os = "linux")]
+static OWN_SIGNAL_CODE: AtomicI32 = AtomicI32::new(0);
+
+thread_local! {
+    // Whether the current thread is symbolizing a report. Its samples are
+    // dropped meanwhile, as the unwinder could re-enter the locks of the
+    // dynamic loader which the symbolizer holds.
+    static SYMBOLIZING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` without taking samples of the current thread.
+pub(crate) fn symbolizing<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
 
-    old_sigaction: Option<signal::SigAction>,
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SYMBOLIZING.with(|symbolizing| symbolizing.set(self.0));
+        }
+    }
+
+    let _restore = Restore(SYMBOLIZING.with(|symbolizing| symbolizing.replace(true)));
+    f()
+}
+
+pub struct Profiler {
+    // The samples, which the aggregator drains the buffers into. The signal
+    // handler never takes this lock, so building a report doesn't block it.
+    data: Arc<Mutex<Collector<UnresolvedFrames>>>,
+
+    max_depth: usize,
+    // The signal handler writes the samples into them, so their storage is
+    // reserved for `max_depth` frames in advance, and the number of samples of
+    // their shards is given by the frequency.
+    buffers: Arc<SampleBuffers<UnresolvedFrames>>,
+    shard_capacity: usize,
+    // The samples which the aggregator drained while the collector was locked,
+    // e.g. by a report being built. They're added to it once it's unlocked.
+    staged: Arc<Mutex<HashMap<UnresolvedFrames, isize>>>,
+    aggregator: Option<Aggregator>,
+
+    signal: c_int,
+    old_sigaction: Option<libc::sigaction>,
     running: bool,
 
-    #[cfg(feature = "frame-pointer")]
+    // The `si_code` of the signals sent by the profiler, if other signals
+    // should be forwarded to the previous handler.
+    #[cfg(target_os = "linux")]
+    chain_signal_code: Option<c_int>,
+
+    #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
     on_stack: bool,
 
     #[cfg(any(
@@ -45,13 +104,37 @@ pub struct Profiler {
         target_arch = "loongarch64"
     ))]
     blocklist_segments: Vec<(usize, usize)>,
+
+    function_blocklist: FunctionBlocklist,
+
+    // The patterns of the blocklists, which are resolved again when libraries
+    // are loaded or unloaded, and the generation of the libraries they were
+    // resolved with.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "loongarch64"
+    ))]
+    blocklist: Vec<String>,
+    function_blocklist_patterns: Vec<(String, BlocklistAction)>,
+    modules_generation: u64,
 }
 
 #[derive(Clone)]
 pub struct ProfilerGuardBuilder {
     frequency: c_int,
+    timer_backend: TimerBackend,
+    max_depth: usize,
+    signal: c_int,
+    jitter: f64,
+    validator: Validator,
+    unwinder: SelectedUnwinder,
+
+    #[cfg(target_os = "linux")]
+    chain_previous_handler: bool,
 
-    #[cfg(feature = "frame-pointer")]
+    #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
     on_stack: bool,
 
     #[cfg(any(
@@ -60,15 +143,26 @@ pub struct ProfilerGuardBuilder {
         target_arch = "riscv64",
         target_arch = "loongarch64"
     ))]
-    blocklist_segments: Vec<(usize, usize)>,
+    blocklist: Vec<String>,
+
+    function_blocklist: Vec<(String, BlocklistAction)>,
 }
 
 impl Default for ProfilerGuardBuilder {
     fn default() -> ProfilerGuardBuilder {
         ProfilerGuardBuilder {
             frequency: 99,
+            timer_backend: TimerBackend::default(),
+            max_depth: MAX_DEPTH,
+            signal: libc::SIGPROF,
+            jitter: 0.0,
+            validator: Validator::default(),
+            unwinder: SelectedUnwinder::Builtin(Unwinder::default()),
 
-            #[cfg(feature = "frame-pointer")]
+            #[cfg(target_os = "linux")]
+            chain_previous_handler: false,
+
+            #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
             on_stack: false,
 
             #[cfg(any(
@@ -77,27 +171,133 @@ impl Default for ProfilerGuardBuilder {
                 target_arch = "riscv64",
                 target_arch = "loongarch64"
             ))]
-            blocklist_segments: Vec::new(),
+            blocklist: Vec::new(),
+
+            function_blocklist: Vec::new(),
         }
     }
 }
 
 impl ProfilerGuardBuilder {
+    /// Sets the number of samples per second, between 1 and
+    /// [`MAX_FREQUENCY`](crate::MAX_FREQUENCY). The default is 99.
     pub fn frequency(self, frequency: c_int) -> Self {
         Self { frequency, ..self }
     }
 
-    #[cfg(feature = "frame-pointer")]
+    /// Sets the source of the profiling signal. See [`TimerBackend`] for the
+    /// available choices. The default is a process-wide `ITIMER_PROF` timer.
+    pub fn timer_backend(self, timer_backend: TimerBackend) -> Self {
+        Self {
+            timer_backend,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of frames recorded in a sample. Deeper stacks
+    /// are truncated. The storage of the profiler is sized for this depth, so
+    /// a smaller value also reduces its memory usage.
+    ///
+    /// The default is [`MAX_DEPTH`](crate::MAX_DEPTH).
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    /// Randomizes the interval between two samples, which is drawn uniformly between
+    /// `1 - jitter` and `1 + jitter` times the mean interval of the frequency. It avoids the
+    /// aliasing with periodic workloads, e.g. an event loop which wakes up every 10ms, which
+    /// would always be sampled at the same point otherwise. The mean interval is unchanged, so
+    /// each sample still stands for the same time.
+    ///
+    /// The jitter must be between 0 and 1. The default is 0, i.e. a fixed interval.
+    pub fn jitter(self, jitter: f64) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// Sets how the unwinders check that the memory they read is mapped. See
+    /// [`Validator`] for the available choices. The default writes it to a
+    /// pipe.
+    ///
+    /// The validator is shared by the whole process: it's selected when the
+    /// profiler is built, and kept after it's dropped.
+    pub fn validator(self, validator: Validator) -> Self {
+        Self { validator, ..self }
+    }
+
+    /// Sets the unwinder which walks the stacks of the samples. See [`Unwinder`] for the ones
+    /// which can be compiled in. The default is `Framehop` with the `framehop-unwinder` feature,
+    /// `FramePointer` with the `frame-pointer` one, and `Libgcc` otherwise.
+    ///
+    /// The unwinder is shared by the whole process, as the validator is.
+    pub fn unwinder(self, unwinder: Unwinder) -> Self {
+        Self {
+            unwinder: SelectedUnwinder::Builtin(unwinder),
+            ..self
+        }
+    }
+
+    /// Sets a custom unwinder, e.g. one which knows about the stacks of green threads, instead of
+    /// one of the builtin ones. It must meet the async-signal-safety contract of
+    /// [`Trace`](crate::unwind::Trace), as it runs in the signal handler.
+    ///
+    /// The frames are copied into the samples, so they must be `Copy` and fit in
+    /// [`CUSTOM_FRAME_WORDS`](crate::unwind::CUSTOM_FRAME_WORDS) machine words, which is checked
+    /// when it's compiled. With `on_stack`, the handler runs on an alternate stack, so the
+    /// unwinder must start from the signal context.
+    pub fn custom_unwinder<T>(self) -> Self
+    where
+        T: Trace + 'static,
+        T::Frame: Copy + Send + Sync + 'static,
+    {
+        Self {
+            unwinder: SelectedUnwinder::Custom(CustomUnwinder::of::<T>()),
+            ..self
+        }
+    }
+
+    /// Sets the signal which drives the sampling. The default is `SIGPROF`.
+    ///
+    /// Another signal, such as a real-time one like `libc::SIGRTMIN() + 1`,
+    /// avoids the conflicts with other profilers in the same process which
+    /// rely on `SIGPROF`, e.g. gperftools or the Go runtime. With the
+    /// `Process` timer backend, it's sent by a `CLOCK_PROCESS_CPUTIME_ID` timer
+    /// instead of `ITIMER_PROF`.
+    #[cfg(target_os = "linux")]
+    pub fn signal(self, signal: c_int) -> Self {
+        Self { signal, ..self }
+    }
+
+    /// Sets whether the signals which were not sent by the profiler are
+    /// forwarded to the handler installed before it. Otherwise, they are
+    /// taken as samples, as the profiler owns the signal while it's running.
+    ///
+    /// The default action of the signal is not taken: if there was no
+    /// handler, these signals are ignored.
+    #[cfg(target_os = "linux")]
+    pub fn chain_previous_handler(self, chain_previous_handler: bool) -> Self {
+        Self {
+            chain_previous_handler,
+            ..self
+        }
+    }
+
+    #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
     /// Sets whether to use an alternate signal stack via `SA_ONSTACK`.
     ///
-    /// This is only available and only works correctly when the `frame-pointer` feature is enabled.
+    /// This is only available when the `frame-pointer` or the `framehop-unwinder` feature is
+    /// enabled.
     ///
-    /// The `backtrace-rs` unwinder ignores the signal context and unwinds the current stack. Using
-    /// an alternate stack with it would produce meaningless results. The `frame-pointer` unwinder,
-    /// however, uses the provided `ucontext` to correctly walk the original application stack.
+    /// The `Libgcc` unwinder ignores the signal context and unwinds the current stack. Using
+    /// an alternate stack with it would produce meaningless results. The `FramePointer` and
+    /// `Framehop` unwinders, however, use the provided `ucontext` to correctly walk the original
+    /// application stack.
     ///
     /// This should be enabled when the profiler is used in an environment
     /// with small stacks (e.g., inside a Go program) to prevent stack overflow.
+    ///
+    /// `build` fails with `Error::UnsupportedOption` if it's enabled with the
+    /// `Libgcc` unwinder, which is the only one on the architectures the
+    /// other unwinders don't support.
     pub fn on_stack(self, on_stack: bool) -> Self {
         Self { on_stack, ..self }
     }
@@ -108,43 +308,83 @@ impl ProfilerGuardBuilder {
         target_arch = "riscv64",
         target_arch = "loongarch64"
     ))]
+    /// Skips the samples whose first frame is in a library whose name contains
+    /// one of the patterns. `build` fails with `Error::UnmatchedBlocklist` if a
+    /// pattern matches no library loaded when it's called.
     pub fn blocklist<T: AsRef<str>>(self, blocklist: &[T]) -> Self {
-        let blocklist_segments = {
-            let mut segments = Vec::new();
-            TargetSharedLibrary::each(|shlib| {
-                let in_blocklist = match shlib.name().to_str() {
-                    Some(name) => {
-                        let mut in_blocklist = false;
-                        for blocked_name in blocklist.iter() {
-                            if name.contains(blocked_name.as_ref()) {
-                                in_blocklist = true;
-                            }
-                        }
+        let blocklist = blocklist
+            .iter()
+            .map(|blocked_name| blocked_name.as_ref().to_owned())
+            .collect();
 
-                        in_blocklist
-                    }
+        Self { blocklist, ..self }
+    }
 
-                    None => false,
-                };
-                if in_blocklist {
-                    for seg in shlib.segments() {
-                        let avam = seg.actual_virtual_memory_address(shlib);
-                        let start = avam.0;
-                        let end = start + seg.len();
-                        segments.push((start, end));
-                    }
-                }
-            });
-            segments
-        };
+    /// Blocklists the functions whose demangled name contains one of the patterns, e.g.
+    /// `"log::"` for a logging crate linked into the executable. The samples which have one of
+    /// them in their stack are dropped or truncated at it, according to `action`. It can be
+    /// called again to blocklist other functions with the other action.
+    ///
+    /// The address ranges of the functions are resolved from the symbol tables when the profiler
+    /// starts, so that they can be checked in the signal handler. `build` fails with
+    /// `Error::UnmatchedBlocklist` if a pattern matches no function, e.g. in a stripped binary.
+    pub fn blocklist_functions<T: AsRef<str>>(
+        mut self,
+        patterns: &[T],
+        action: BlocklistAction,
+    ) -> Self {
+        self.function_blocklist.extend(
+            patterns
+                .iter()
+                .map(|pattern| (pattern.as_ref().to_owned(), action)),
+        );
+        self
+    }
 
-        Self {
-            blocklist_segments,
-            ..self
+    /// Checks the settings, before the profiler is started.
+    fn validate(&self) -> Result<()> {
+        if !(1..=MAX_FREQUENCY).contains(&self.frequency) {
+            return Err(Error::InvalidFrequency(self.frequency));
         }
+        // it also rejects NaN
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(Error::InvalidJitter(self.jitter));
+        }
+        if self.max_depth == 0 {
+            return Err(Error::InvalidMaxDepth(self.max_depth));
+        }
+
+        #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
+        if self.on_stack && !self.unwinder.uses_context() {
+            return Err(Error::UnsupportedOption {
+                option: "on_stack",
+                unwinder: self.unwinder.name(),
+            });
+        }
+
+        Ok(())
     }
+
     pub fn build(self) -> Result<ProfilerGuard<'static>> {
+        self.validate()?;
+
+        let modules_generation = crate::modules::generation();
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ))]
+        let blocklist_segments = {
+            let (segments, unmatched) = blocklist_segments(&self.blocklist);
+            if let Some(pattern) = unmatched {
+                return Err(Error::UnmatchedBlocklist(pattern.clone()));
+            }
+            segments
+        };
+        let function_blocklist = FunctionBlocklist::resolve(&self.function_blocklist)?;
         trigger_lazy();
+        crate::register_thread();
 
         match PROFILER.write().as_mut() {
             Err(err) => {
@@ -152,7 +392,22 @@ impl ProfilerGuardBuilder {
                 Err(Error::CreatingError)
             }
             Ok(profiler) => {
-                #[cfg(feature = "frame-pointer")]
+                // nothing is changed while another guard is alive
+                if profiler.running {
+                    return Err(Error::Running);
+                }
+                profiler.resize(self.max_depth, self.frequency)?;
+                profiler.signal = self.signal;
+                let timer_backend = self.timer_backend.resolve();
+
+                #[cfg(target_os = "linux")]
+                {
+                    profiler.chain_signal_code = self
+                        .chain_previous_handler
+                        .then(|| crate::timer::signal_code(timer_backend, self.signal));
+                }
+
+                #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
                 {
                     profiler.on_stack = self.on_stack;
                 }
@@ -164,15 +419,29 @@ impl ProfilerGuardBuilder {
                     target_arch = "loongarch64"
                 ))]
                 {
-                    profiler.blocklist_segments = self.blocklist_segments;
+                    profiler.blocklist_segments = blocklist_segments;
+                    profiler.blocklist = self.blocklist;
                 }
-
-                match profiler.start() {
-                    Ok(()) => Ok(ProfilerGuard::<'static> {
+                profiler.function_blocklist = function_blocklist;
+                profiler.function_blocklist_patterns = self.function_blocklist;
+                profiler.modules_generation = modules_generation;
+
+                profiler.start()?;
+                // the validator and the unwinder are only switched once the
+                // profiler is started, before the first signal
+                self.validator.select();
+                self.unwinder.select();
+                TraceImpl::init();
+                match Timer::new(self.frequency, timer_backend, self.signal, self.jitter) {
+                    Ok(timer) => Ok(ProfilerGuard::<'static> {
                         profiler: &PROFILER,
-                        timer: Some(Timer::new(self.frequency)),
+                        timer: Some(timer),
                     }),
-                    Err(err) => Err(err),
+                    Err(err) => {
+                        // no signal is sent without the timer
+                        profiler.stop()?;
+                        Err(Error::TimerError(err))
+                    }
                 }
             }
         }
@@ -185,25 +454,202 @@ pub struct ProfilerGuard<'a> {
     timer: Option<Timer>,
 }
 
+/// Returns the address ranges of the segments of the libraries whose name
+/// contains one of the patterns, and the first pattern which matches no library.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64"
+))]
+fn blocklist_segments(blocklist: &[String]) -> (Vec<(usize, usize)>, Option<&String>) {
+    let mut matched = vec![false; blocklist.len()];
+    let mut segments = Vec::new();
+    TargetSharedLibrary::each(|shlib| {
+        let in_blocklist = match shlib.name().to_str() {
+            Some(name) => {
+                let mut in_blocklist = false;
+                for (blocked_name, matched) in blocklist.iter().zip(matched.iter_mut()) {
+                    if name.contains(blocked_name.as_str()) {
+                        in_blocklist = true;
+                        *matched = true;
+                    }
+                }
+
+                in_blocklist
+            }
+
+            None => false,
+        };
+        if in_blocklist {
+            for seg in shlib.segments() {
+                let avam = seg.actual_virtual_memory_address(shlib);
+                let start = avam.0;
+                let end = start + seg.len();
+                segments.push((start, end));
+            }
+        }
+    });
+
+    let unmatched = blocklist
+        .iter()
+        .zip(matched)
+        .find(|(_, matched)| !matched)
+        .map(|(blocked_name, _)| blocked_name);
+    (segments, unmatched)
+}
+
 fn trigger_lazy() {
     let _ = backtrace::Backtrace::new();
     let _profiler = PROFILER.read();
-    TraceImpl::init();
 }
 
-impl ProfilerGuard<'_> {
+impl<'a> ProfilerGuard<'a> {
     /// Start profiling with given sample frequency.
     pub fn new(frequency: c_int) -> Result<ProfilerGuard<'static>> {
         ProfilerGuardBuilder::default().frequency(frequency).build()
     }
 
     /// Generate a report
-    pub fn report(&self) -> ReportBuilder {
+    pub fn report(&self) -> ReportBuilder<'_> {
         ReportBuilder::new(
             self.profiler,
             self.timer.as_ref().map(Timer::timing).unwrap_or_default(),
+            COUNTERS.snapshot(),
         )
     }
+
+    /// Returns the source of the profiling signal. It differs from the one given to the builder
+    /// if that one couldn't be used, e.g. `PerfEvent` falls back to `Process` when
+    /// `perf_event_open` is not permitted.
+    pub fn timer_backend(&self) -> TimerBackend {
+        self.timer
+            .as_ref()
+            .map_or(TimerBackend::default(), |timer| timer.backend)
+    }
+
+    /// Stop taking samples until `resume` is called. The samples collected so far are kept, and
+    /// the time spent paused is not counted in the duration of the report.
+    pub fn pause(&mut self) {
+        if let Some(timer) = self.timer.as_mut() {
+            timer.pause();
+        }
+    }
+
+    /// Continue taking samples after `pause`.
+    pub fn resume(&mut self) -> Result<()> {
+        if let Some(timer) = self.timer.as_mut() {
+            timer.resume().map_err(Error::TimerError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take out the samples collected since the profiler was started, or since the last call of
+    /// this function, and generate a report of them. The profiler keeps running with an empty
+    /// collector, and the timing of the returned report only covers this interval.
+    pub fn report_and_reset(&mut self) -> Result<ReportBuilder<'a>> {
+        match self.profiler.read().as_ref() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                Err(Error::CreatingError)
+            }
+            Ok(profiler) => {
+                // The new collector is created before the samples are taken
+                // out, so that the aggregator isn't blocked while it's being
+                // allocated.
+                let data = profiler.reset(profiler.new_collector()?);
+                let timing = self.timer.as_mut().map(Timer::reset).unwrap_or_default();
+
+                Ok(ReportBuilder::from_snapshot(data, timing, COUNTERS.reset()))
+            }
+        }
+    }
+
+    /// Updates the blocklists, and the unwind information of the `framehop` unwinder, with the
+    /// libraries which were loaded or unloaded since the profiler was started, e.g. plugins opened
+    /// with `dlopen`. It returns whether any library changed. Otherwise, it only reads a counter
+    /// of the dynamic loader, so it can be called periodically.
+    ///
+    /// The new lists are built before the profiler is locked to swap them in, and the signal
+    /// handler never waits for this lock: the samples taken meanwhile are counted in
+    /// `SampleStats::dropped_lock_contention`.
+    ///
+    /// With [`Validator::MapsSnapshot`], it also takes a new snapshot of the mappings, whether a
+    /// library changed or not.
+    pub fn refresh_modules(&self) -> Result<bool> {
+        // the mappings also change without loading a library, e.g. when a
+        // thread is spawned
+        Validator::current().prepare();
+
+        let generation = crate::modules::generation();
+        #[cfg_attr(
+            not(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64",
+                target_arch = "loongarch64"
+            )),
+            allow(unused_variables)
+        )]
+        let (blocklist, function_blocklist) = match self.profiler.read().as_ref() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                return Err(Error::CreatingError);
+            }
+            Ok(profiler) if profiler.modules_generation == generation => return Ok(false),
+            Ok(profiler) => {
+                #[cfg(any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ))]
+                let blocklist = profiler.blocklist.clone();
+                #[cfg(not(any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                )))]
+                let blocklist: Vec<String> = Vec::new();
+
+                (blocklist, profiler.function_blocklist_patterns.clone())
+            }
+        };
+
+        TraceImpl::refresh();
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ))]
+        let mut blocklist_segments = blocklist_segments(&blocklist).0;
+        let mut function_blocklist = FunctionBlocklist::refresh(&function_blocklist);
+
+        match self.profiler.write().as_mut() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                return Err(Error::CreatingError);
+            }
+            Ok(profiler) => {
+                // the previous lists are swapped out, and freed after the
+                // lock is released
+                #[cfg(any(
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64",
+                    target_arch = "loongarch64"
+                ))]
+                std::mem::swap(&mut profiler.blocklist_segments, &mut blocklist_segments);
+                std::mem::swap(&mut profiler.function_blocklist, &mut function_blocklist);
+                profiler.modules_generation = generation;
+            }
+        }
+
+        Ok(true)
+    }
 }
 
 impl<'a> Drop for ProfilerGuard<'a> {
@@ -246,12 +692,12 @@ fn write_thread_name_fallback(current_thread: libc::pthread_t, name: &mut [libc:
 }
 
 #[cfg(not(all(any(target_os = "linux", target_os = "macos"), target_env = "gnu")))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+pub(crate) fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
     write_thread_name_fallback(current_thread, name);
 }
 
 #[cfg(all(any(target_os = "linux", target_os = "macos"), target_env = "gnu"))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+pub(crate) fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
     let name_ptr = name as *mut [libc::c_char] as *mut libc::c_char;
     let ret = unsafe { libc::pthread_getname_np(current_thread, name_ptr, MAX_THREAD_NAME) };
 
@@ -315,14 +761,48 @@ impl Drop for ErrnoProtector {
 )]
 #[allow(clippy::unnecessary_cast)]
 extern "C" fn perf_signal_handler(
-    _signal: c_int,
-    _siginfo: *mut libc::siginfo_t,
+    signal: c_int,
+    siginfo: *mut libc::siginfo_t,
     ucontext: *mut libc::c_void,
 ) {
     let _errno = ErrnoProtector::new();
 
-    if let Some(mut guard) = PROFILER.try_write() {
-        if let Ok(profiler) = guard.as_mut() {
+    #[cfg(target_os = "linux")]
+    {
+        let previous = PREVIOUS_HANDLER.load(Ordering::SeqCst);
+        if previous != 0
+            && !siginfo.is_null()
+            && !crate::timer::is_own_signal(
+                unsafe { &*siginfo },
+                OWN_SIGNAL_CODE.load(Ordering::SeqCst),
+            )
+        {
+            unsafe {
+                if PREVIOUS_SIGINFO.load(Ordering::SeqCst) {
+                    let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                        std::mem::transmute(previous);
+                    handler(signal, siginfo, ucontext);
+                } else {
+                    let handler: extern "C" fn(c_int) = std::mem::transmute(previous);
+                    handler(signal);
+                }
+            }
+            return;
+        }
+    }
+
+    crate::timer::rearm(siginfo);
+
+    if SYMBOLIZING.try_with(Cell::get).unwrap_or_default() {
+        COUNTERS.symbolizing();
+        return;
+    }
+
+    // The profiler is only locked for writing while it's started, stopped or
+    // refreshed, so the handlers running at the same time don't exclude each
+    // other.
+    if let Some(guard) = PROFILER.try_read() {
+        if let Ok(profiler) = guard.as_ref() {
             #[cfg(any(
                 target_arch = "x86_64",
                 target_arch = "aarch64",
@@ -375,54 +855,100 @@ extern "C" fn perf_signal_handler(
                 let addr = unsafe { (*ucontext).uc_mcontext.__pc as usize };
 
                 if profiler.is_blocklisted(addr) {
+                    COUNTERS.blocklisted();
                     return;
                 }
             }
 
-            let mut bt: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]> =
-                SmallVec::with_capacity(MAX_DEPTH);
-            let mut index = 0;
+            let max_depth = profiler.max_depth;
+
+            let mut dropped = false;
+            #[allow(unused_variables)]
+            let unwinder = SelectedUnwinder::current();
 
             let sample_timestamp: SystemTime = SystemTime::now();
-            TraceImpl::trace(ucontext, |frame| {
-                #[cfg(feature = "frame-pointer")]
-                {
-                    let ip = crate::backtrace::Frame::ip(frame);
-                    if profiler.is_blocklisted(ip) {
-                        return false;
+            // The sample is unwound right into a slot of the buffers, whose
+            // storage is reserved in advance. Nothing is unwound if they're
+            // full.
+            let pushed = profiler.buffers.push(|sample| {
+                let bt = &mut sample.frames;
+                bt.clear();
+                let mut walk = StackWalk::new(&profiler.function_blocklist, max_depth);
+                TraceImpl::trace(ucontext, |frame| {
+                    // the frame pointers can't be trusted in the blocklisted
+                    // libraries, which may be built without them
+                    #[cfg(all(
+                        any(
+                            target_arch = "x86_64",
+                            target_arch = "aarch64",
+                            target_arch = "riscv64",
+                            target_arch = "loongarch64"
+                        ),
+                        feature = "frame-pointer"
+                    ))]
+                    if matches!(unwinder, SelectedUnwinder::Builtin(Unwinder::FramePointer)) {
+                        let ip = crate::backtrace::Frame::ip(frame);
+                        if profiler.is_blocklisted(ip) {
+                            COUNTERS.frame_pointer_truncated();
+                            return false;
+                        }
                     }
-                }
 
-                if index < MAX_DEPTH {
-                    bt.push(frame.clone());
-                    index += 1;
-                    true
-                } else {
-                    false
-                }
-            });
+                    walk.visit(bt, frame, crate::backtrace::Frame::ip(frame))
+                });
 
-            let current_thread = unsafe { libc::pthread_self() };
-            let mut name = [0; MAX_THREAD_NAME];
-            let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+                if walk.dropped {
+                    dropped = true;
+                    return false;
+                }
 
-            write_thread_name(current_thread, &mut name);
+                let current_thread = unsafe { libc::pthread_self() };
+                let mut name = [0; MAX_THREAD_NAME];
+                let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+
+                write_thread_name(current_thread, &mut name);
+
+                let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
+                sample.set_thread(
+                    name.to_bytes(),
+                    current_thread as u64,
+                    sample_timestamp,
+                    crate::labels::current(),
+                    crate::shadow::current(),
+                );
+                true
+            });
 
-            let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
-            profiler.sample(bt, name.to_bytes(), current_thread as u64, sample_timestamp);
+            if dropped {
+                COUNTERS.blocklisted();
+            } else if !pushed {
+                COUNTERS.buffer_full();
+            }
         }
+    } else {
+        COUNTERS.lock_contention();
     }
 }
 
 impl Profiler {
     fn new() -> Result<Self> {
         Ok(Profiler {
-            data: Collector::new()?,
-            sample_counter: 0,
+            data: Arc::new(Mutex::new(Collector::new_with(|| {
+                UnresolvedFrames::with_max_depth(MAX_DEPTH)
+            })?)),
+            max_depth: MAX_DEPTH,
+            buffers: new_buffers(MAX_DEPTH, SHARD_CAPACITY),
+            shard_capacity: SHARD_CAPACITY,
+            staged: Arc::default(),
+            aggregator: None,
+            signal: libc::SIGPROF,
             old_sigaction: None,
             running: false,
 
-            #[cfg(feature = "frame-pointer")]
+            #[cfg(target_os = "linux")]
+            chain_signal_code: None,
+
+            #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
             on_stack: false,
 
             #[cfg(any(
@@ -432,6 +958,18 @@ impl Profiler {
                 target_arch = "loongarch64"
             ))]
             blocklist_segments: Vec::new(),
+
+            function_blocklist: FunctionBlocklist::default(),
+
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64",
+                target_arch = "loongarch64"
+            ))]
+            blocklist: Vec::new(),
+            function_blocklist_patterns: Vec::new(),
+            modules_generation: 0,
         })
     }
 
@@ -457,7 +995,18 @@ impl Profiler {
         if self.running {
             Err(Error::Running)
         } else {
+            COUNTERS.reset();
+            let aggregator = {
+                let (buffers, data, staged) =
+                    (self.buffers.clone(), self.data.clone(), self.staged.clone());
+                Aggregator::spawn(move |stopped| {
+                    aggregate(&buffers, &data, &staged, stopped);
+                    crate::backtrace::resolve_stacks();
+                })?
+            };
+            // the aggregator is stopped if the handler can't be registered
             self.register_signal_handler()?;
+            self.aggregator = Some(aggregator);
             self.running = true;
 
             Ok(())
@@ -465,17 +1014,63 @@ impl Profiler {
     }
 
     fn init(&mut self) -> Result<()> {
-        self.sample_counter = 0;
-        self.data = Collector::new()?;
+        *lock(&self.data) = self.new_collector()?;
+        lock(&self.staged).clear();
+        self.buffers.drain(|_| {});
         self.running = false;
 
         Ok(())
     }
 
+    fn new_collector(&self) -> Result<Collector<UnresolvedFrames>> {
+        let max_depth = self.max_depth;
+        Ok(Collector::new_with(|| {
+            UnresolvedFrames::with_max_depth(max_depth)
+        })?)
+    }
+
+    /// Calls `f` with the collected samples, including the ones which are
+    /// still in the buffers or staged. Neither the aggregator nor the signal
+    /// handler waits for it.
+    pub(crate) fn with_data<T>(&self, f: impl FnOnce(&mut Collector<UnresolvedFrames>) -> T) -> T {
+        let mut data = lock(&self.data);
+        merge(&mut lock(&self.staged), &mut data);
+        drain(&self.buffers, &mut data);
+        f(&mut data)
+    }
+
+    /// Replaces the collected samples with `data`, and returns them.
+    fn reset(&self, data: Collector<UnresolvedFrames>) -> Collector<UnresolvedFrames> {
+        self.with_data(|current| std::mem::replace(current, data))
+    }
+
+    /// Resizes the storage of the profiler for stacks of `max_depth` frames,
+    /// and its buffers for the samples taken at `frequency`. It can only be
+    /// called when the profiler is not running.
+    fn resize(&mut self, max_depth: usize, frequency: c_int) -> Result<()> {
+        if self.running {
+            return Err(Error::Running);
+        }
+        let shard_capacity = crate::buffer::shard_capacity(frequency);
+        if self.max_depth == max_depth && self.shard_capacity == shard_capacity {
+            return Ok(());
+        }
+        if self.max_depth != max_depth {
+            self.max_depth = max_depth;
+            *lock(&self.data) = self.new_collector()?;
+        }
+        self.shard_capacity = shard_capacity;
+        self.buffers = new_buffers(max_depth, shard_capacity);
+
+        Ok(())
+    }
+
     pub fn stop(&mut self) -> Result<()> {
         log::info!("stopping cpu profiler");
         if self.running {
             self.unregister_signal_handler()?;
+            // the last samples are drained when it's stopped
+            self.aggregator = None;
             self.init()?;
 
             Ok(())
@@ -485,44 +1080,116 @@ impl Profiler {
     }
 
     fn register_signal_handler(&mut self) -> Result<()> {
-        let handler = signal::SigHandler::SigAction(perf_signal_handler);
+        let mut sigaction: libc::sigaction = unsafe { std::mem::zeroed() };
+        sigaction.sa_sigaction = perf_signal_handler as *const () as usize;
         // SA_RESTART will only restart a syscall when it's safe to do so,
         // e.g. when it's a blocking read(2) or write(2). See man 7 signal.
-        let flags = signal::SaFlags::SA_SIGINFO | signal::SaFlags::SA_RESTART;
-        #[cfg(feature = "frame-pointer")]
-        let flags = if self.on_stack {
+        sigaction.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
+        if self.on_stack {
             // SA_ONSTACK will deliver the signal on an alternate stack. This is crucial
             // to prevent a stack overflow if the signal arrives at a thread with
             // a small stack, which is common when use pprof-rs in Go runtimes.
-            flags | signal::SaFlags::SA_ONSTACK
-        } else {
-            flags
-        };
-        let sigaction = signal::SigAction::new(handler, flags, signal::SigSet::empty());
-        let old_action = unsafe { signal::sigaction(signal::SIGPROF, &sigaction) }?;
+            sigaction.sa_flags |= libc::SA_ONSTACK;
+        }
+        unsafe { libc::sigemptyset(&mut sigaction.sa_mask) };
+
+        // The previous handler has to be known before ours is installed, so
+        // that no signal of someone else is taken as a sample.
+        let mut old_action: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(self.signal, std::ptr::null(), &mut old_action) } != 0 {
+            return Err(nix::Error::last().into());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(code) = self.chain_signal_code {
+            if old_action.sa_sigaction != libc::SIG_DFL && old_action.sa_sigaction != libc::SIG_IGN
+            {
+                OWN_SIGNAL_CODE.store(code, Ordering::SeqCst);
+                PREVIOUS_SIGINFO.store(
+                    old_action.sa_flags & libc::SA_SIGINFO != 0,
+                    Ordering::SeqCst,
+                );
+                PREVIOUS_HANDLER.store(old_action.sa_sigaction, Ordering::SeqCst);
+            }
+        }
+
+        if unsafe { libc::sigaction(self.signal, &sigaction, &mut old_action) } != 0 {
+            #[cfg(target_os = "linux")]
+            PREVIOUS_HANDLER.store(0, Ordering::SeqCst);
+            return Err(nix::Error::last().into());
+        }
         self.old_sigaction = Some(old_action);
         Ok(())
     }
 
     fn unregister_signal_handler(&mut self) -> Result<()> {
         if let Some(old_action) = self.old_sigaction.take() {
-            unsafe { signal::sigaction(signal::SIGPROF, &old_action) }?;
+            if unsafe { libc::sigaction(self.signal, &old_action, std::ptr::null_mut()) } != 0 {
+                return Err(nix::Error::last().into());
+            }
         }
+        #[cfg(target_os = "linux")]
+        PREVIOUS_HANDLER.store(0, Ordering::SeqCst);
         Ok(())
     }
+}
+
+/// Creates the buffers of the signal handler, for stacks of `max_depth` frames.
+fn new_buffers(max_depth: usize, shard_capacity: usize) -> Arc<SampleBuffers<UnresolvedFrames>> {
+    Arc::new(SampleBuffers::new_with(shard_capacity, || {
+        UnresolvedFrames::with_max_depth(max_depth)
+    }))
+}
 
-    // This function has to be AS-safe
-    pub fn sample(
-        &mut self,
-        backtrace: SmallVec<[<TraceImpl as Trace>::Frame; MAX_DEPTH]>,
-        thread_name: &[u8],
-        thread_id: u64,
-        sample_timestamp: SystemTime,
-    ) {
-        let frames = UnresolvedFrames::new(backtrace, thread_name, thread_id, sample_timestamp);
-        self.sample_counter += 1;
+/// Locks `mutex`, even if a thread panicked while holding it: the samples
+/// which were collected are still reported.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-        if let Ok(()) = self.data.add(frames, 1) {}
+/// Moves the samples of the buffers into the collector.
+fn drain(buffers: &SampleBuffers<UnresolvedFrames>, data: &mut Collector<UnresolvedFrames>) {
+    buffers.drain(|sample| {
+        if data.add(sample, 1).is_err() {
+            COUNTERS.collector_error();
+        }
+    });
+}
+
+/// Moves the staged samples into the collector.
+fn merge(staged: &mut HashMap<UnresolvedFrames, isize>, data: &mut Collector<UnresolvedFrames>) {
+    for (sample, count) in staged.drain() {
+        if data.add(&sample, count).is_err() {
+            COUNTERS.collector_error();
+        }
+    }
+}
+
+/// Drains the buffers for the aggregator. If the collector is locked, e.g. by a
+/// report being built, the samples are staged instead of waiting for it, so
+/// that the buffers don't fill up meanwhile. The last time, it waits for the
+/// collector, so that no sample is left staged.
+fn aggregate(
+    buffers: &SampleBuffers<UnresolvedFrames>,
+    data: &Mutex<Collector<UnresolvedFrames>>,
+    staged: &Mutex<HashMap<UnresolvedFrames, isize>>,
+    last: bool,
+) {
+    let data = match data.try_lock() {
+        Ok(data) => Some(data),
+        Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+        Err(TryLockError::WouldBlock) if last => Some(lock(data)),
+        Err(TryLockError::WouldBlock) => None,
+    };
+
+    // the collector is always locked before the staged samples
+    let mut staged = lock(staged);
+    match data {
+        Some(mut data) => {
+            merge(&mut staged, &mut data);
+            drain(buffers, &mut data);
+        }
+        None => buffers.drain(|sample| *staged.entry(sample.clone()).or_default() += 1),
     }
 }
 
@@ -531,16 +1198,20 @@ pub mod tests {
     use super::*;
 
     struct AllocDetector {
-        should_count_alloc: std::sync::atomic::AtomicBool,
         alloc_count: std::sync::atomic::AtomicUsize,
     }
 
+    thread_local! {
+        // Only the allocations of the thread which enabled it are counted, so
+        // that the other tests and the threads of the profiler, e.g. the
+        // aggregator, which run meanwhile aren't counted. It has a const
+        // initializer and no destructor, so that the allocator can read it.
+        static SHOULD_COUNT_ALLOC: Cell<bool> = const { Cell::new(false) };
+    }
+
     unsafe impl std::alloc::GlobalAlloc for AllocDetector {
         unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
-            if self
-                .should_count_alloc
-                .load(std::sync::atomic::Ordering::SeqCst)
-            {
+            if SHOULD_COUNT_ALLOC.try_with(Cell::get).unwrap_or(false) {
                 self.alloc_count
                     .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
             }
@@ -554,13 +1225,11 @@ pub mod tests {
     }
     impl AllocDetector {
         fn enable_count_alloc(&self) {
-            self.should_count_alloc
-                .store(true, std::sync::atomic::Ordering::SeqCst);
+            SHOULD_COUNT_ALLOC.with(|count| count.set(true));
         }
 
         fn disable_count_alloc(&self) {
-            self.should_count_alloc
-                .store(false, std::sync::atomic::Ordering::SeqCst);
+            SHOULD_COUNT_ALLOC.with(|count| count.set(false));
         }
 
         fn alloc_count(&self) -> usize {
@@ -570,18 +1239,18 @@ pub mod tests {
 
     #[global_allocator]
     static ALLOC: AllocDetector = AllocDetector {
-        should_count_alloc: std::sync::atomic::AtomicBool::new(false),
         alloc_count: std::sync::atomic::AtomicUsize::new(0),
     };
 
     #[test]
     fn test_no_alloc_during_unwind() {
-        // This test cannot run parallelly because it requires the global allocator to
-        // record the allocation count.
-
+        // The signal of the process-wide timer is mostly delivered to this
+        // thread, which is the one running, so the signal handler runs on it
+        // and its allocations are counted.
         trigger_lazy();
+        TraceImpl::init();
         PROFILER.write().as_mut().unwrap().start().unwrap();
-        let timer = Timer::new(999);
+        let timer = Timer::new(999, TimerBackend::Process, libc::SIGPROF, 0.0).unwrap();
         let start = std::time::Instant::now();
         ALLOC.enable_count_alloc();
 
@@ -598,4 +1267,85 @@ pub mod tests {
         drop(timer);
         PROFILER.write().as_mut().unwrap().stop().unwrap();
     }
+
+    #[test]
+    fn test_aggregate_while_locked() {
+        let buffers = new_buffers(4, SHARD_CAPACITY);
+        let data = Mutex::new(Collector::new_with(|| UnresolvedFrames::with_max_depth(4)).unwrap());
+        let staged = Mutex::new(HashMap::new());
+        let count = |data: &Mutex<Collector<UnresolvedFrames>>| -> isize {
+            lock(data)
+                .try_iter()
+                .unwrap()
+                .map(|entry| entry.count)
+                .sum()
+        };
+
+        assert!(buffers.push(|_| true));
+        {
+            // e.g. a report is being built
+            let _locked = lock(&data);
+            aggregate(&buffers, &data, &staged, false);
+        }
+        assert_eq!(lock(&staged).values().sum::<isize>(), 1);
+        assert_eq!(count(&data), 0);
+
+        assert!(buffers.push(|_| true));
+        aggregate(&buffers, &data, &staged, false);
+        assert!(lock(&staged).is_empty());
+        assert_eq!(count(&data), 2);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        // the settings are checked before the profiler is started, so this
+        // test doesn't interfere with the others
+        for frequency in [0, -1, MAX_FREQUENCY + 1] {
+            let err = ProfilerGuardBuilder::default()
+                .frequency(frequency)
+                .build()
+                .err();
+            assert!(matches!(err, Some(Error::InvalidFrequency(f)) if f == frequency));
+        }
+
+        for jitter in [-0.1, 1.5, f64::NAN] {
+            let err = ProfilerGuardBuilder::default().jitter(jitter).build().err();
+            assert!(matches!(err, Some(Error::InvalidJitter(_))));
+        }
+
+        let err = ProfilerGuardBuilder::default().max_depth(0).build().err();
+        assert!(matches!(err, Some(Error::InvalidMaxDepth(0))));
+
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "loongarch64"
+        ))]
+        {
+            let err = ProfilerGuardBuilder::default()
+                .blocklist(&["libc", "no-such-library"])
+                .build()
+                .err();
+            assert!(
+                matches!(err, Some(Error::UnmatchedBlocklist(ref pattern)) if pattern == "no-such-library")
+            );
+        }
+
+        #[cfg(any(feature = "frame-pointer", feature = "framehop-unwinder"))]
+        {
+            let err = ProfilerGuardBuilder::default()
+                .unwinder(Unwinder::Libgcc)
+                .on_stack(true)
+                .build()
+                .err();
+            assert!(matches!(
+                err,
+                Some(Error::UnsupportedOption {
+                    option: "on_stack",
+                    unwinder: "libgcc"
+                })
+            ));
+        }
+    }
 }
@@ -5,12 +5,16 @@ use std::fmt::{Debug, Formatter};
 
 use spin::RwLock;
 
+use crate::collector::Collector;
 use crate::frames::{Frames, UnresolvedFrames};
 use crate::profiler::Profiler;
+use crate::stats::SampleStats;
 use crate::timer::ReportTiming;
 
 use crate::{Error, Result};
 
+pub(crate) mod events;
+
 /// The final presentation of a report which is actually an `HashMap` from `Frames` to isize (count).
 pub struct Report {
     /// Key is a backtrace captured by profiler and value is count of it.
@@ -18,6 +22,9 @@ pub struct Report {
 
     /// Collection frequency, start time, duration.
     pub timing: ReportTiming,
+
+    /// Counts of the samples which were dropped or truncated.
+    pub stats: SampleStats,
 }
 
 /// The presentation of an unsymbolicated report which is actually an `HashMap` from `UnresolvedFrames` to isize (count).
@@ -27,23 +34,71 @@ pub struct UnresolvedReport {
 
     /// Collection frequency, start time, duration.
     pub timing: ReportTiming,
+
+    /// Counts of the samples which were dropped or truncated.
+    pub stats: SampleStats,
 }
 
 type FramesPostProcessor = Box<dyn Fn(&mut Frames)>;
 
-/// A builder of `Report` and `UnresolvedReport`. It builds report from a running `Profiler`.
+enum ReportSource<'a> {
+    Profiler(&'a RwLock<Result<Profiler>>),
+    Snapshot(Collector<UnresolvedFrames>),
+}
+
+/// A builder of `Report` and `UnresolvedReport`. It builds report from a running `Profiler`, or
+/// from the samples taken out of it by `ProfilerGuard::report_and_reset`.
 pub struct ReportBuilder<'a> {
     frames_post_processor: Option<FramesPostProcessor>,
-    profiler: &'a RwLock<Result<Profiler>>,
+    source: ReportSource<'a>,
     timing: ReportTiming,
+    stats: SampleStats,
 }
 
 impl<'a> ReportBuilder<'a> {
-    pub(crate) fn new(profiler: &'a RwLock<Result<Profiler>>, timing: ReportTiming) -> Self {
+    pub(crate) fn new(
+        profiler: &'a RwLock<Result<Profiler>>,
+        timing: ReportTiming,
+        stats: SampleStats,
+    ) -> Self {
         Self {
             frames_post_processor: None,
-            profiler,
+            source: ReportSource::Profiler(profiler),
             timing,
+            stats,
+        }
+    }
+
+    pub(crate) fn from_snapshot(
+        data: Collector<UnresolvedFrames>,
+        timing: ReportTiming,
+        stats: SampleStats,
+    ) -> Self {
+        Self {
+            frames_post_processor: None,
+            source: ReportSource::Snapshot(data),
+            timing,
+            stats,
+        }
+    }
+
+    /// Calls `f` with the collected samples. The signal handler keeps taking
+    /// samples meanwhile.
+    fn with_data<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Collector<UnresolvedFrames>) -> Result<T>,
+    {
+        let profiler = match &self.source {
+            ReportSource::Profiler(profiler) => profiler,
+            ReportSource::Snapshot(data) => return f(data),
+        };
+
+        match profiler.read().as_ref() {
+            Err(err) => {
+                log::error!("Error in creating profiler: {}", err);
+                Err(Error::CreatingError)
+            }
+            Ok(profiler) => profiler.with_data(|data| f(data)),
         }
     }
 
@@ -63,80 +118,76 @@ impl<'a> ReportBuilder<'a> {
     pub fn build_unresolved(&self) -> Result<UnresolvedReport> {
         let mut hash_map = HashMap::new();
 
-        match self.profiler.read().as_ref() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let key = &entry.item;
-                        match hash_map.get_mut(key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key.clone(), count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
-                            }
+        self.with_data(|data| {
+            data.try_iter()?.for_each(|entry| {
+                let count = entry.count;
+                if count > 0 {
+                    let key = entry.item;
+                    match hash_map.get_mut(&key) {
+                        Some(value) => {
+                            *value += count;
+                        }
+                        None => {
+                            match hash_map.insert(key, count) {
+                                None => {}
+                                Some(_) => {
+                                    unreachable!();
+                                }
+                            };
                         }
                     }
-                });
+                }
+            });
 
-                Ok(UnresolvedReport {
-                    data: hash_map,
-                    timing: self.timing.clone(),
-                })
-            }
-        }
+            Ok(())
+        })?;
+
+        Ok(UnresolvedReport {
+            data: hash_map,
+            timing: self.timing.clone(),
+            stats: self.stats.clone(),
+        })
     }
 
     /// Build a `Report`.
     pub fn build(&self) -> Result<Report> {
         let mut hash_map = HashMap::new();
 
-        match self.profiler.write().as_mut() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let mut key = Frames::from(entry.item.clone());
-                        if let Some(processor) = &self.frames_post_processor {
-                            processor(&mut key);
-                        }
+        // The samples are copied out before they're symbolized, so that the
+        // aggregator isn't blocked meanwhile.
+        let entries: Vec<_> = self.with_data(|data| Ok(data.try_iter()?.collect()))?;
+
+        crate::profiler::symbolizing(|| {
+            entries.into_iter().for_each(|entry| {
+                let count = entry.count;
+                if count > 0 {
+                    let mut key = Frames::from(entry.item);
+                    if let Some(processor) = &self.frames_post_processor {
+                        processor(&mut key);
+                    }
 
-                        match hash_map.get_mut(&key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key, count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
-                            }
+                    match hash_map.get_mut(&key) {
+                        Some(value) => {
+                            *value += count;
+                        }
+                        None => {
+                            match hash_map.insert(key, count) {
+                                None => {}
+                                Some(_) => {
+                                    unreachable!();
+                                }
+                            };
                         }
                     }
-                });
-
-                Ok(Report {
-                    data: hash_map,
-                    timing: self.timing.clone(),
-                })
-            }
-        }
+                }
+            });
+        });
+
+        Ok(Report {
+            data: hash_map,
+            timing: self.timing.clone(),
+            stats: self.stats.clone(),
+        })
     }
 }
 
@@ -158,7 +209,7 @@ impl Debug for Report {
 }
 
 #[cfg(feature = "flamegraph")]
-mod flamegraph {
+pub(crate) mod flamegraph {
     use super::*;
     use inferno::flamegraph;
     use std::fmt::Write;
@@ -181,164 +232,271 @@ mod flamegraph {
         where
             W: std::io::Write,
         {
-            let lines: Vec<String> = self
-                .data
-                .iter()
-                .map(|(key, value)| {
-                    let mut line = key.thread_name_or_id();
-                    line.push(';');
+            self.flamegraph_with_label_roots(writer, options, &[])
+        }
 
-                    for frame in key.frames.iter().rev() {
-                        for symbol in frame.iter().rev() {
-                            write!(&mut line, "{};", symbol).unwrap();
-                        }
+        /// same as `flamegraph_with_options`, but the labels with the given `keys` are added as
+        /// root frames above the thread, in this order. The samples are then grouped by these
+        /// labels first, e.g. by the task with `pprof::tokio::TASK_NAME`. A sample without one
+        /// of the labels doesn't get a frame for it.
+        pub fn flamegraph_with_label_roots<W>(
+            &self,
+            writer: W,
+            options: &mut flamegraph::Options,
+            keys: &[&str],
+        ) -> Result<()>
+        where
+            W: std::io::Write,
+        {
+            write_flamegraph(
+                writer,
+                options,
+                self.data.iter().map(|(key, value)| (key, *value as i64)),
+                keys,
+                true,
+            )
+        }
+    }
+
+    /// Writes an svg flamegraph of the stacks of `samples`, each of which is
+    /// weighted by its value. See `Report::flamegraph_with_label_roots` for
+    /// `keys`. The thread is added as a root frame if `with_thread` is set.
+    pub(crate) fn write_flamegraph<'a, W, I>(
+        writer: W,
+        options: &mut flamegraph::Options,
+        samples: I,
+        keys: &[&str],
+        with_thread: bool,
+    ) -> Result<()>
+    where
+        W: std::io::Write,
+        I: Iterator<Item = (&'a Frames, i64)>,
+    {
+        let lines: Vec<String> = samples
+            .map(|(key, value)| {
+                let mut line = String::new();
+                for root in keys {
+                    if let Some((label_key, label_value)) =
+                        key.labels.iter().find(|(label_key, _)| label_key == root)
+                    {
+                        write!(&mut line, "{}={};", label_key, label_value).unwrap();
                     }
+                }
 
-                    line.pop().unwrap_or_default();
-                    write!(&mut line, " {}", value).unwrap();
+                if with_thread {
+                    line.push_str(&key.thread_name_or_id());
+                    line.push(';');
+                }
 
-                    line
-                })
-                .collect();
-            if !lines.is_empty() {
-                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).unwrap();
-                // TODO: handle this error
-            }
+                for frame in key.frames.iter().rev() {
+                    for symbol in frame.iter().rev() {
+                        write!(&mut line, "{};", symbol).unwrap();
+                    }
+                }
 
-            Ok(())
+                line.pop().unwrap_or_default();
+                write!(&mut line, " {}", value).unwrap();
+
+                line
+            })
+            .collect();
+        if !lines.is_empty() {
+            flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).unwrap();
+            // TODO: handle this error
         }
+
+        Ok(())
     }
 }
 
 #[cfg(feature = "_protobuf")]
 #[allow(clippy::useless_conversion)]
 #[allow(clippy::needless_update)]
-mod protobuf {
+pub(crate) mod protobuf {
     use super::*;
+    use crate::frames::Symbol;
     use crate::protos;
-    use std::collections::HashSet;
+    use crate::timer::TimerBackend;
     use std::time::SystemTime;
 
     const SAMPLES: &str = "samples";
     const COUNT: &str = "count";
     const CPU: &str = "cpu";
+    #[cfg(target_os = "linux")]
+    const WALL: &str = "wall";
     const NANOSECONDS: &str = "nanoseconds";
     const THREAD: &str = "thread";
 
-    impl Report {
-        /// `pprof` will generate google's pprof format report.
-        pub fn pprof(&self) -> crate::Result<protos::Profile> {
-            let mut dedup_str = HashSet::new();
-            for key in self.data.keys() {
-                dedup_str.insert(key.thread_name_or_id());
-                for frame in key.frames.iter() {
-                    for symbol in frame {
-                        dedup_str.insert(symbol.name());
-                        dedup_str.insert(symbol.sys_name().into_owned());
-                        dedup_str.insert(symbol.filename().into_owned());
-                    }
-                }
-            }
-            dedup_str.insert(SAMPLES.into());
-            dedup_str.insert(COUNT.into());
-            dedup_str.insert(CPU.into());
-            dedup_str.insert(NANOSECONDS.into());
-            dedup_str.insert(THREAD.into());
+    /// Builds a `protos::Profile` sample by sample. The functions and locations
+    /// are shared by all samples, and the strings are interned as they come.
+    pub(crate) struct ProfileBuilder {
+        strings: HashMap<String, i64>,
+        string_table: Vec<String>,
+        functions: HashMap<String, u64>,
+        function_table: Vec<protos::Function>,
+        location_table: Vec<protos::Location>,
+        samples: Vec<protos::Sample>,
+    }
+
+    impl ProfileBuilder {
+        pub fn new() -> Self {
+            let mut builder = ProfileBuilder {
+                strings: HashMap::new(),
+                string_table: Vec::new(),
+                functions: HashMap::new(),
+                function_table: Vec::new(),
+                location_table: Vec::new(),
+                samples: Vec::new(),
+            };
             // string table's first element must be an empty string
-            let mut str_tbl = vec!["".to_owned()];
-            str_tbl.extend(dedup_str.into_iter());
+            builder.string("");
+            builder
+        }
 
-            let mut strings = HashMap::new();
-            for (index, name) in str_tbl.iter().enumerate() {
-                strings.insert(name.as_str(), index);
+        /// Returns the index of `value` in the string table.
+        pub fn string(&mut self, value: &str) -> i64 {
+            if let Some(index) = self.strings.get(value) {
+                return *index;
             }
 
-            let mut samples = vec![];
-            let mut loc_tbl = vec![];
-            let mut fn_tbl = vec![];
-            let mut functions = HashMap::new();
-            for (key, count) in self.data.iter() {
-                let mut locs = vec![];
-                for frame in key.frames.iter() {
-                    for symbol in frame {
-                        let name = symbol.name();
-                        if let Some(loc_idx) = functions.get(&name) {
-                            locs.push(*loc_idx);
-                            continue;
-                        }
-                        let sys_name = symbol.sys_name();
-                        let filename = symbol.filename();
-                        let lineno = symbol.lineno();
-                        let function_id = fn_tbl.len() as u64 + 1;
-                        let function = protos::Function {
-                            id: function_id,
-                            name: *strings.get(name.as_str()).unwrap() as i64,
-                            system_name: *strings.get(sys_name.as_ref()).unwrap() as i64,
-                            filename: *strings.get(filename.as_ref()).unwrap() as i64,
-                            ..protos::Function::default()
-                        };
-                        functions.insert(name, function_id);
-                        let line = protos::Line {
-                            function_id,
-                            line: lineno as i64,
-                            ..protos::Line::default()
-                        };
-                        let loc = protos::Location {
-                            id: function_id,
-                            line: vec![line].into(),
-                            ..protos::Location::default()
-                        };
-                        // the fn_tbl has the same length with loc_tbl
-                        fn_tbl.push(function);
-                        loc_tbl.push(loc);
-                        // current frame locations
-                        locs.push(function_id);
-                    }
+            let index = self.string_table.len() as i64;
+            self.string_table.push(value.to_owned());
+            self.strings.insert(value.to_owned(), index);
+            index
+        }
+
+        fn location(&mut self, symbol: &Symbol) -> u64 {
+            let name = symbol.name();
+            if let Some(loc_idx) = self.functions.get(&name) {
+                return *loc_idx;
+            }
+
+            let function_id = self.function_table.len() as u64 + 1;
+            let function = protos::Function {
+                id: function_id,
+                name: self.string(&name),
+                system_name: self.string(&symbol.sys_name()),
+                filename: self.string(&symbol.filename()),
+                ..protos::Function::default()
+            };
+            let line = protos::Line {
+                function_id,
+                line: symbol.lineno() as i64,
+                ..protos::Line::default()
+            };
+            let loc = protos::Location {
+                id: function_id,
+                line: vec![line].into(),
+                ..protos::Location::default()
+            };
+            // the function_table has the same length with location_table
+            self.function_table.push(function);
+            self.location_table.push(loc);
+            self.functions.insert(name, function_id);
+
+            function_id
+        }
+
+        /// Adds a sample of the stack of `frames`, with a value for each of
+        /// the sample types, and the given labels.
+        pub fn add_sample(&mut self, frames: &Frames, values: Vec<i64>, labels: &[(&str, &str)]) {
+            let mut locs = vec![];
+            for frame in frames.frames.iter() {
+                for symbol in frame {
+                    locs.push(self.location(symbol));
                 }
-                let thread_name = protos::Label {
-                    key: *strings.get(THREAD).unwrap() as i64,
-                    str: *strings.get(&key.thread_name_or_id().as_str()).unwrap() as i64,
-                    ..protos::Label::default()
-                };
-                let sample = protos::Sample {
-                    location_id: locs,
-                    value: vec![
-                        *count as i64,
-                        *count as i64 * 1_000_000_000 / self.timing.frequency as i64,
-                    ],
-                    label: vec![thread_name].into(),
-                    ..Default::default()
-                };
-                samples.push(sample);
             }
-            let samples_value = protos::ValueType {
-                ty: *strings.get(SAMPLES).unwrap() as i64,
-                unit: *strings.get(COUNT).unwrap() as i64,
+
+            let label = labels
+                .iter()
+                .map(|(key, value)| protos::Label {
+                    key: self.string(key),
+                    str: self.string(value),
+                    ..protos::Label::default()
+                })
+                .collect::<Vec<_>>();
+
+            self.samples.push(protos::Sample {
+                location_id: locs,
+                value: values,
+                label: label.into(),
                 ..Default::default()
-            };
-            let time_value = protos::ValueType {
-                ty: *strings.get(CPU).unwrap() as i64,
-                unit: *strings.get(NANOSECONDS).unwrap() as i64,
+            });
+        }
+
+        /// Builds the profile. `sample_types` and `period_type` are pairs of
+        /// type and unit.
+        pub fn build(
+            mut self,
+            sample_types: &[(&str, &str)],
+            period_type: (&str, &str),
+            period: i64,
+            start_time: SystemTime,
+            duration: std::time::Duration,
+        ) -> protos::Profile {
+            let sample_type = sample_types
+                .iter()
+                .map(|(ty, unit)| protos::ValueType {
+                    ty: self.string(ty),
+                    unit: self.string(unit),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            let period_type = protos::ValueType {
+                ty: self.string(period_type.0),
+                unit: self.string(period_type.1),
                 ..Default::default()
             };
-            let profile = protos::Profile {
-                sample_type: vec![samples_value, time_value.clone()].into(),
-                sample: samples.into(),
-                string_table: str_tbl.into(),
-                function: fn_tbl.into(),
-                location: loc_tbl.into(),
-                time_nanos: self
-                    .timing
-                    .start_time
+
+            protos::Profile {
+                sample_type: sample_type.into(),
+                sample: self.samples.into(),
+                string_table: self.string_table.into(),
+                function: self.function_table.into(),
+                location: self.location_table.into(),
+                time_nanos: start_time
                     .duration_since(SystemTime::UNIX_EPOCH)
                     .unwrap_or_default()
                     .as_nanos() as i64,
-                duration_nanos: self.timing.duration.as_nanos() as i64,
-                period_type: Some(time_value).into(),
-                period: 1_000_000_000 / self.timing.frequency as i64,
+                duration_nanos: duration.as_nanos() as i64,
+                period_type: Some(period_type).into(),
+                period,
                 ..protos::Profile::default()
+            }
+        }
+    }
+
+    impl Report {
+        /// `pprof` will generate google's pprof format report.
+        pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            let time_type = match self.timing.backend {
+                #[cfg(target_os = "linux")]
+                TimerBackend::WallClock => WALL,
+                _ => CPU,
             };
-            Ok(profile)
+            let period = self.timing.period().as_nanos() as i64;
+
+            let mut builder = ProfileBuilder::new();
+            for (key, count) in self.data.iter() {
+                let thread_name = key.thread_name_or_id();
+                let mut labels = vec![(THREAD, thread_name.as_str())];
+                labels.extend(
+                    key.labels
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), value.as_str())),
+                );
+
+                let time = *count as i64 * period;
+                builder.add_sample(key, vec![*count as i64, time], &labels);
+            }
+
+            Ok(builder.build(
+                &[(SAMPLES, COUNT), (time_type, NANOSECONDS)],
+                (time_type, NANOSECONDS),
+                period,
+                self.timing.start_time,
+                self.timing.duration,
+            ))
         }
     }
 }
@@ -0,0 +1,223 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The state and the reports shared by the event profilers, i.e. the
+//! contention and the off-CPU profilers. They record a number of events and
+//! the total time they took for each stack. Only the capture of the events is
+//! specific to each of them.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backtrace::{Trace, TraceImpl};
+use crate::collector::{Collector, Entry};
+use crate::error::{Error, Result};
+use crate::frames::{Frames, UnresolvedFrames};
+use crate::MAX_DEPTH;
+
+/// The counts of a stack in the report of an event profiler.
+pub(crate) trait EventCounts: Copy + Default {
+    fn new(events: i64, delay: i64) -> Self;
+
+    /// Number of events.
+    fn events(&self) -> i64;
+
+    /// Total time of the events, in nanoseconds.
+    fn delay(&self) -> i64;
+}
+
+struct State {
+    start_time: SystemTime,
+    start_instant: Instant,
+    events: Collector<UnresolvedFrames>,
+    delay: Collector<UnresolvedFrames>,
+}
+
+/// The state of an event profiler, which lives in a static of its module.
+pub(crate) struct EventProfiler {
+    enabled: AtomicBool,
+    max_depth: AtomicUsize,
+    state: Mutex<Option<State>>,
+    /// The frames up to the last one whose name contains it are the frames of
+    /// the profiler, and are stripped from the reports.
+    module: &'static str,
+}
+
+/// The symbolized samples of an event profiler.
+pub(crate) struct EventData<C> {
+    pub data: HashMap<Frames, C>,
+    pub start_time: SystemTime,
+    pub duration: Duration,
+}
+
+impl EventProfiler {
+    pub const fn new(module: &'static str) -> Self {
+        EventProfiler {
+            enabled: AtomicBool::new(false),
+            max_depth: AtomicUsize::new(MAX_DEPTH),
+            state: Mutex::new(None),
+            module,
+        }
+    }
+
+    // the events are recorded from arbitrary code, which shouldn't panic
+    // because another thread did while holding the state
+    fn lock(&self) -> MutexGuard<'_, Option<State>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the events should be captured. It costs an atomic load.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// The maximum number of frames of the captured stacks.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn start(&self, max_depth: usize) -> Result<()> {
+        TraceImpl::init();
+
+        let mut state = self.lock();
+        if state.is_some() {
+            return Err(Error::Running);
+        }
+
+        *state = Some(State {
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            events: Collector::new_with(|| UnresolvedFrames::with_max_depth(max_depth))?,
+            delay: Collector::new_with(|| UnresolvedFrames::with_max_depth(max_depth))?,
+        });
+        self.max_depth.store(max_depth, Ordering::Relaxed);
+        self.enabled.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        *self.lock() = None;
+    }
+
+    /// Records an event of the stack `key` which took `delay`. The profiler
+    /// may have been restarted since the stack was captured, in which case
+    /// it's recorded anyway, and it's ignored once stopped.
+    pub fn record(&self, key: &UnresolvedFrames, delay: Duration) -> std::io::Result<()> {
+        let delay = delay.as_nanos().min(isize::MAX as u128) as isize;
+        match self.lock().as_mut() {
+            Some(state) => {
+                state.events.add(key, 1)?;
+                state.delay.add(key, delay)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Symbolizes the events recorded so far.
+    pub fn report<C: EventCounts>(&self) -> Result<EventData<C>> {
+        // The entries are copied out, and symbolized once the lock is released,
+        // so that the recording threads aren't blocked meanwhile.
+        let (events, delay, start_time, duration) = {
+            let state = self.lock();
+            let state = state.as_ref().ok_or(Error::NotRunning)?;
+            let copy = |collector: &Collector<UnresolvedFrames>| -> Result<Vec<_>> {
+                Ok(collector
+                    .try_iter()?
+                    .filter(|entry| entry.count > 0)
+                    .collect())
+            };
+            (
+                copy(&state.events)?,
+                copy(&state.delay)?,
+                state.start_time,
+                state.start_instant.elapsed(),
+            )
+        };
+
+        let mut data: HashMap<Frames, C> = HashMap::new();
+        let mut add = |entries: Vec<Entry<UnresolvedFrames>>, update: fn(C, i64) -> C| {
+            for entry in entries {
+                let mut key = Frames::from(entry.item);
+                key.strip_profiler_frames(|name| name.contains(self.module));
+                let counts = data.entry(key).or_default();
+                *counts = update(*counts, entry.count as i64);
+            }
+        };
+        add(events, |counts, events| {
+            C::new(counts.events() + events, counts.delay())
+        });
+        add(delay, |counts, delay| {
+            C::new(counts.events(), counts.delay() + delay)
+        });
+
+        Ok(EventData {
+            data,
+            start_time,
+            duration,
+        })
+    }
+}
+
+pub(crate) fn fmt<C: Debug>(data: &HashMap<Frames, C>, f: &mut Formatter) -> std::fmt::Result {
+    for (key, val) in data.iter() {
+        write!(f, "{:?} {:?}", key, val)?;
+        writeln!(f)?;
+    }
+
+    Ok(())
+}
+
+/// Sums the counts of all the stacks.
+#[cfg(test)]
+pub(crate) fn total<C: EventCounts>(data: &HashMap<Frames, C>) -> C {
+    data.values().fold(C::default(), |total, counts| {
+        C::new(
+            total.events() + counts.events(),
+            total.delay() + counts.delay(),
+        )
+    })
+}
+
+/// Writes an svg flamegraph of the time taken by the events.
+#[cfg(feature = "flamegraph")]
+pub(crate) fn write_flamegraph<C: EventCounts, W: std::io::Write>(
+    data: &HashMap<Frames, C>,
+    writer: W,
+    options: &mut inferno::flamegraph::Options,
+) -> Result<()> {
+    let samples = data.iter().map(|(key, counts)| (key, counts.delay()));
+    super::flamegraph::write_flamegraph(writer, options, samples, &[], true)
+}
+
+/// Builds the pprof profile of the events. `sample_types` are the type and
+/// unit of the number of events and of their time.
+#[cfg(feature = "_protobuf")]
+pub(crate) fn pprof<C: EventCounts>(
+    data: &HashMap<Frames, C>,
+    sample_types: [(&str, &str); 2],
+    period_type: (&str, &str),
+    start_time: SystemTime,
+    duration: Duration,
+) -> crate::protos::Profile {
+    const THREAD: &str = "thread";
+
+    let mut builder = super::protobuf::ProfileBuilder::new();
+    for (key, counts) in data.iter() {
+        let thread_name = key.thread_name_or_id();
+        let mut labels = vec![(THREAD, thread_name.as_str())];
+        labels.extend(
+            key.labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+
+        builder.add_sample(key, vec![counts.events(), counts.delay()], &labels);
+    }
+
+    builder.build(&sample_types, period_type, 1, start_time, duration)
+}
@@ -0,0 +1,407 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Virtual frames of an interpreter or a scripting runtime.
+//!
+//! The native stack of an interpreter is mostly its dispatch loop, which is
+//! the same whatever script it runs. The runtime can keep a shadow stack of
+//! the functions it runs on every thread, with [`push`] and [`pop`]. Every
+//! sample carries the shadow stack of its thread, and its virtual frames are
+//! spliced in as callees of the native frames of the interpreter, which are
+//! given to [`register_interpreter`].
+//!
+//! A virtual frame is a small id chosen by the runtime, e.g. the index of a
+//! function prototype. Its name is only needed when the report is built, so it
+//! can be given lazily with [`register_name`].
+//!
+//! The shadow stacks are only recorded with the `shadow-stack` feature, since
+//! every sample has room for one. Without it, the functions do nothing.
+//!
+//! ```
+//! use pprof::shadow;
+//!
+//! shadow::register_interpreter("interp_loop");
+//!
+//! fn interp_loop(function: u32) {
+//!     let _activation = shadow::enter();
+//!     shadow::register_name(function, "main.lua:handle_request");
+//!     shadow::push(function);
+//!     // run the bytecode of the function
+//!     shadow::pop();
+//! }
+//!
+//! interp_loop(7);
+//! ```
+
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::frames::Symbol;
+
+/// The maximum number of virtual frames of a sample. The frames pushed beyond
+/// it are left out, so that the ones closer to the root are kept.
+pub const MAX_SHADOW_DEPTH: usize = 32;
+
+// the number of virtual frames a sample has room for
+#[cfg(feature = "shadow-stack")]
+const CAPACITY: usize = MAX_SHADOW_DEPTH;
+#[cfg(not(feature = "shadow-stack"))]
+const CAPACITY: usize = 0;
+
+/// The letter of a virtual frame in the `UNWIND_METHODS` label.
+pub(crate) const METHOD_LETTER: char = 'v';
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+struct Entry {
+    id: u32,
+    // the activation of the interpreter which runs the frame
+    activation: u32,
+}
+
+impl Entry {
+    const NONE: Entry = Entry {
+        id: 0,
+        activation: 0,
+    };
+}
+
+/// The shadow stack of a sample, from the root. It's `Copy`, so that the
+/// signal handler can take it without allocating. Only its used entries are
+/// compared and hashed.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowStack {
+    entries: [Entry; CAPACITY],
+    len: usize,
+}
+
+impl Default for ShadowStack {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+impl PartialEq for ShadowStack {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries() == other.entries()
+    }
+}
+
+impl Eq for ShadowStack {}
+
+impl Hash for ShadowStack {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.entries().hash(state);
+    }
+}
+
+impl ShadowStack {
+    const EMPTY: ShadowStack = ShadowStack {
+        entries: [Entry::NONE; CAPACITY],
+        len: 0,
+    };
+
+    fn entries(&self) -> &[Entry] {
+        &self.entries[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts the virtual frames into `frames`, which go from the leaf. The
+    /// frames of the `n`th activation are put right above the `n`th frame of
+    /// the interpreter from the root, and the ones of the activations which
+    /// have no such frame above the innermost one. They're left out if no
+    /// frame of the interpreter is found.
+    pub(crate) fn splice<T>(
+        &self,
+        frames: Vec<T>,
+        is_interpreter: impl Fn(&T) -> bool,
+        mut virtual_frame: impl FnMut(u32) -> T,
+    ) -> Vec<T> {
+        if self.is_empty() {
+            return frames;
+        }
+
+        // the indexes of the frames of the interpreter, from the root
+        let interpreters: Vec<usize> = frames
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, frame)| is_interpreter(frame))
+            .map(|(index, _)| index)
+            .collect();
+        if interpreters.is_empty() {
+            return frames;
+        }
+
+        let mut spliced = Vec::with_capacity(frames.len() + self.len);
+        for (index, frame) in frames.into_iter().enumerate() {
+            if let Some(activation) = interpreters.iter().position(|i| *i == index) {
+                let entries = self.entries().iter().rev().filter(|entry| {
+                    // the activations are counted from 1, and 0 is for the
+                    // frames pushed outside of any
+                    let nth = entry.activation.saturating_sub(1) as usize;
+                    nth.min(interpreters.len() - 1) == activation
+                });
+                spliced.extend(entries.map(|entry| virtual_frame(entry.id)));
+            }
+            spliced.push(frame);
+        }
+        spliced
+    }
+}
+
+// The shadow stack of the current thread. The entries are only written by the
+// thread itself, and the signal handler which interrupts it only reads the
+// ones below `depth`, so they're never read while being written.
+struct Shadow {
+    entries: UnsafeCell<[Entry; CAPACITY]>,
+    // the number of frames pushed, including the ones beyond the maximum depth
+    depth: Cell<usize>,
+    activation: Cell<u32>,
+}
+
+thread_local! {
+    // It has a const initializer and no destructor, so that it can be read in
+    // the signal handler.
+    static SHADOW: Shadow = const {
+        Shadow {
+            entries: UnsafeCell::new([Entry::NONE; CAPACITY]),
+            depth: Cell::new(0),
+            activation: Cell::new(0),
+        }
+    };
+}
+
+/// Pushes the virtual frame `id` on the shadow stack of the current thread,
+/// in the innermost activation of the interpreter.
+pub fn push(id: u32) {
+    let _ = SHADOW.try_with(|shadow| {
+        let depth = shadow.depth.get();
+        let entries = unsafe { &mut *shadow.entries.get() };
+        if let Some(slot) = entries.get_mut(depth) {
+            *slot = Entry {
+                id,
+                activation: shadow.activation.get(),
+            };
+            // the signal handler only sees the entry once it's written
+            compiler_fence(Ordering::SeqCst);
+        }
+        shadow.depth.set(depth + 1);
+    });
+}
+
+/// Pops the last virtual frame from the shadow stack of the current thread.
+pub fn pop() {
+    let _ = SHADOW.try_with(|shadow| shadow.depth.set(shadow.depth.get().saturating_sub(1)));
+}
+
+/// Marks a new activation of the interpreter, until the returned guard is
+/// dropped. An interpreter which is re-entered, e.g. through a native function
+/// which calls back into a script, enters one every time its dispatch loop
+/// starts, so that the frames it pushes are spliced in at the matching frame of
+/// the dispatch loop. Without it, every virtual frame is put above the
+/// outermost one.
+pub fn enter() -> Activation {
+    let previous = SHADOW
+        .try_with(|shadow| {
+            let previous = shadow.activation.get();
+            shadow.activation.set(previous + 1);
+            previous
+        })
+        .unwrap_or(0);
+    Activation(previous)
+}
+
+/// Restores the previous activation when it's dropped.
+#[must_use]
+pub struct Activation(u32);
+
+impl Drop for Activation {
+    fn drop(&mut self) {
+        let _ = SHADOW.try_with(|shadow| shadow.activation.set(self.0));
+    }
+}
+
+/// Returns the shadow stack of the current thread. It's called in the signal
+/// handler.
+pub(crate) fn current() -> ShadowStack {
+    SHADOW
+        .try_with(|shadow| {
+            let mut stack = ShadowStack::EMPTY;
+            let len = std::cmp::min(shadow.depth.get(), CAPACITY);
+            let entries = unsafe { &*shadow.entries.get() };
+            stack.entries[..len].copy_from_slice(&entries[..len]);
+            stack.len = len;
+            stack
+        })
+        .unwrap_or_default()
+}
+
+/// The names of the virtual frames, and the symbols of the native frames of
+/// the interpreters.
+#[derive(Default)]
+pub(crate) struct Registry {
+    names: HashMap<u32, String>,
+    interpreters: Vec<String>,
+}
+
+impl Registry {
+    /// Tells whether a native frame, given by its symbols, is a frame of an
+    /// interpreter.
+    pub fn is_interpreter(&self, symbols: &[Symbol]) -> bool {
+        symbols
+            .iter()
+            .any(|symbol| self.interpreters.contains(&symbol.name()))
+    }
+
+    /// Returns the symbol of a virtual frame.
+    pub fn symbol(&self, id: u32) -> Symbol {
+        let name = match self.names.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("<virtual frame {}>", id),
+        };
+        Symbol {
+            name: Some(name.into_bytes()),
+            addr: None,
+            lineno: None,
+            filename: None,
+        }
+    }
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Default::default);
+
+/// Calls `f` with the registered names and interpreters.
+pub(crate) fn resolve<T>(f: impl FnOnce(&Registry) -> T) -> T {
+    f(&REGISTRY.lock().unwrap())
+}
+
+/// Gives the name of the virtual frame `id`, which the reports show. It can be
+/// given at any time before the report is built, and replaces the previous
+/// one.
+pub fn register_name(id: u32, name: &str) {
+    REGISTRY.lock().unwrap().names.insert(id, name.to_owned());
+}
+
+/// Registers the demangled name of the native function of an interpreter,
+/// usually its dispatch loop, whose frames the virtual frames are spliced in
+/// at.
+pub fn register_interpreter(name: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if !registry
+        .interpreters
+        .iter()
+        .any(|interpreter| interpreter == name)
+    {
+        registry.interpreters.push(name.to_owned());
+    }
+}
+
+#[cfg(all(test, feature = "shadow-stack"))]
+mod tests {
+    use super::*;
+
+    fn stack(entries: &[(u32, u32)]) -> ShadowStack {
+        let mut stack = ShadowStack::EMPTY;
+        for (index, (id, activation)) in entries.iter().enumerate() {
+            stack.entries[index] = Entry {
+                id: *id,
+                activation: *activation,
+            };
+        }
+        stack.len = entries.len();
+        stack
+    }
+
+    fn splice(stack: &ShadowStack, frames: &[&str]) -> Vec<String> {
+        let frames = frames.iter().map(|frame| frame.to_string()).collect();
+        stack.splice(
+            frames,
+            |frame| frame == "interp_loop",
+            |id| format!("v{}", id),
+        )
+    }
+
+    #[test]
+    fn push_and_pop() {
+        std::thread::spawn(|| {
+            assert!(current().is_empty());
+
+            push(1);
+            {
+                let _activation = enter();
+                push(2);
+                assert_eq!(current(), stack(&[(1, 0), (2, 1)]));
+                pop();
+            }
+            push(3);
+            assert_eq!(current(), stack(&[(1, 0), (3, 0)]));
+
+            pop();
+            pop();
+            pop();
+            assert_eq!(current(), ShadowStack::default());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn beyond_max_depth() {
+        std::thread::spawn(|| {
+            for id in 0..MAX_SHADOW_DEPTH as u32 + 2 {
+                push(id);
+            }
+            let ids: Vec<u32> = current().entries().iter().map(|entry| entry.id).collect();
+            assert_eq!(ids, (0..MAX_SHADOW_DEPTH as u32).collect::<Vec<_>>());
+
+            // the frames beyond the maximum depth are popped first
+            pop();
+            pop();
+            pop();
+            assert_eq!(current().len, MAX_SHADOW_DEPTH - 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn splice_at_interpreter() {
+        let frames = ["leaf", "interp_loop", "main"];
+        assert_eq!(
+            splice(&stack(&[(1, 0), (2, 0)]), &frames),
+            vec!["leaf", "v2", "v1", "interp_loop", "main"]
+        );
+
+        // they're left out without a frame of the interpreter
+        assert_eq!(
+            splice(&stack(&[(1, 0)]), &["leaf", "main"]),
+            vec!["leaf", "main"]
+        );
+    }
+
+    #[test]
+    fn splice_activations() {
+        let frames = ["leaf", "interp_loop", "native", "interp_loop", "main"];
+        assert_eq!(
+            splice(&stack(&[(1, 1), (2, 1), (3, 2), (4, 3)]), &frames),
+            vec![
+                "leaf",
+                "v4",
+                "v3",
+                "interp_loop",
+                "native",
+                "v2",
+                "v1",
+                "interp_loop",
+                "main"
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters of the samples which were lost, or only partially recorded, by the
+/// signal handler. A profile with many of them may not be representative.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleStats {
+    /// Samples dropped because the profiler was locked, e.g. while it was
+    /// refreshed by `refresh_modules`.
+    pub dropped_lock_contention: u64,
+    /// Samples dropped because they were taken on a thread which was
+    /// symbolizing a report, as the unwinder could deadlock on the locks of
+    /// the dynamic loader which the symbolizer holds.
+    pub dropped_symbolizing: u64,
+    /// Samples dropped because the buffers of the signal handler were full,
+    /// i.e. they were taken faster than the aggregator thread drained them.
+    pub dropped_buffer_full: u64,
+    /// Samples dropped because the interrupted instruction was in a
+    /// blocklisted library, or a frame was in a function blocklisted with
+    /// `BlocklistAction::DropSample`.
+    pub dropped_blocklisted: u64,
+    /// Samples dropped because they couldn't be stored, e.g. when writing the
+    /// temporary file of the collector failed.
+    pub dropped_collector_error: u64,
+    /// Samples whose frame-pointer walk stopped early at a blocklisted frame.
+    /// They are kept with the frames collected until then.
+    pub truncated_frame_pointer: u64,
+}
+
+/// The async-signal-safe counterpart of `SampleStats`, which is updated by the
+/// signal handler.
+pub(crate) struct Counters {
+    dropped_lock_contention: AtomicUsize,
+    dropped_symbolizing: AtomicUsize,
+    dropped_buffer_full: AtomicUsize,
+    dropped_blocklisted: AtomicUsize,
+    dropped_collector_error: AtomicUsize,
+    truncated_frame_pointer: AtomicUsize,
+}
+
+pub(crate) static COUNTERS: Counters = Counters::new();
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            dropped_lock_contention: AtomicUsize::new(0),
+            dropped_symbolizing: AtomicUsize::new(0),
+            dropped_buffer_full: AtomicUsize::new(0),
+            dropped_blocklisted: AtomicUsize::new(0),
+            dropped_collector_error: AtomicUsize::new(0),
+            truncated_frame_pointer: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lock_contention(&self) {
+        self.dropped_lock_contention.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn symbolizing(&self) {
+        self.dropped_symbolizing.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn buffer_full(&self) {
+        self.dropped_buffer_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn blocklisted(&self) {
+        self.dropped_blocklisted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn collector_error(&self) {
+        self.dropped_collector_error.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn frame_pointer_truncated(&self) {
+        self.truncated_frame_pointer.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> SampleStats {
+        SampleStats {
+            dropped_lock_contention: self.dropped_lock_contention.load(Ordering::Relaxed) as u64,
+            dropped_symbolizing: self.dropped_symbolizing.load(Ordering::Relaxed) as u64,
+            dropped_buffer_full: self.dropped_buffer_full.load(Ordering::Relaxed) as u64,
+            dropped_blocklisted: self.dropped_blocklisted.load(Ordering::Relaxed) as u64,
+            dropped_collector_error: self.dropped_collector_error.load(Ordering::Relaxed) as u64,
+            truncated_frame_pointer: self.truncated_frame_pointer.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    /// Returns the current counts, and sets them to zero.
+    pub fn reset(&self) -> SampleStats {
+        SampleStats {
+            dropped_lock_contention: self.dropped_lock_contention.swap(0, Ordering::Relaxed) as u64,
+            dropped_symbolizing: self.dropped_symbolizing.swap(0, Ordering::Relaxed) as u64,
+            dropped_buffer_full: self.dropped_buffer_full.swap(0, Ordering::Relaxed) as u64,
+            dropped_blocklisted: self.dropped_blocklisted.swap(0, Ordering::Relaxed) as u64,
+            dropped_collector_error: self.dropped_collector_error.swap(0, Ordering::Relaxed) as u64,
+            truncated_frame_pointer: self.truncated_frame_pointer.swap(0, Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_counters() {
+        let counters = Counters::new();
+        counters.lock_contention();
+        counters.lock_contention();
+        counters.collector_error();
+        counters.buffer_full();
+        counters.symbolizing();
+
+        let expected = SampleStats {
+            dropped_lock_contention: 2,
+            dropped_symbolizing: 1,
+            dropped_buffer_full: 1,
+            dropped_collector_error: 1,
+            ..SampleStats::default()
+        };
+        assert_eq!(counters.snapshot(), expected);
+        assert_eq!(counters.reset(), expected);
+        assert_eq!(counters.snapshot(), SampleStats::default());
+    }
+}
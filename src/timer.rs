@@ -1,9 +1,27 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::io;
 use std::os::raw::c_int;
 use std::ptr::null_mut;
 use std::time::{Duration, Instant, SystemTime};
 
+mod jitter;
+#[cfg(target_os = "linux")]
+mod perf_event;
+#[cfg(target_os = "linux")]
+mod posix;
+#[cfg(target_os = "linux")]
+mod thread_cpu;
+#[cfg(target_os = "linux")]
+mod wall_clock;
+
+#[cfg(target_os = "linux")]
+use posix::ProcessTimer;
+#[cfg(target_os = "linux")]
+use thread_cpu::ThreadTimers;
+#[cfg(target_os = "linux")]
+use wall_clock::WallClockTimer;
+
 #[repr(C)]
 #[derive(Clone)]
 struct Timeval {
@@ -24,85 +42,484 @@ extern "C" {
 
 const ITIMER_PROF: c_int = 2;
 
+/// Arms `ITIMER_PROF` to expire after `value`, and then every `interval`, in
+/// microseconds.
+fn set_itimer_prof(value: i64, interval: i64) -> io::Result<()> {
+    let it_interval = Timeval {
+        tv_sec: interval / 1e6 as i64,
+        tv_usec: interval % 1e6 as i64,
+    };
+    let it_value = Timeval {
+        tv_sec: value / 1e6 as i64,
+        tv_usec: value % 1e6 as i64,
+    };
+
+    let ret = unsafe {
+        setitimer(
+            ITIMER_PROF,
+            &mut Itimerval {
+                it_interval,
+                it_value,
+            },
+            null_mut(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The source of the `SIGPROF` signals which drive the sampling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimerBackend {
+    /// A single process-wide `ITIMER_PROF` timer. The kernel sends the signal to
+    /// whichever thread is running when it expires, so busy threads may be
+    /// under-sampled when many cores are in use.
+    #[default]
+    Process,
+
+    /// One timer on the `CLOCK_THREAD_CPUTIME_ID` clock of every thread, which
+    /// is delivered to that thread through `SIGEV_THREAD_ID`. The number of
+    /// samples of each thread matches its own CPU time. Threads created after
+    /// the profiler started are picked up within 100ms.
+    #[cfg(target_os = "linux")]
+    PerThread,
+
+    /// Wall-clock profiling: a background thread sends the signal to every
+    /// thread at a fixed real-time interval, whether it is running or blocked
+    /// on I/O or locks. The samples are labeled as "wall" time in `pprof()`.
+    ///
+    /// Blocking system calls which can't be restarted, such as `epoll_wait`,
+    /// will return `EINTR` more often in this mode.
+    #[cfg(target_os = "linux")]
+    WallClock,
+
+    /// One `perf_event_open` counter on the cpu clock of every thread, whose
+    /// overflows are delivered to that thread. It's as accurate as `PerThread`,
+    /// and more precise on some kernels. Threads created after the profiler
+    /// started are picked up within 100ms.
+    ///
+    /// If `perf_event_open` is not permitted, e.g. by `perf_event_paranoid` or
+    /// a seccomp policy, the profiler falls back to `Process`. The backend
+    /// which was used is given by `ProfilerGuard::timer_backend` and in the
+    /// timing of the reports.
+    #[cfg(target_os = "linux")]
+    PerfEvent,
+}
+
+impl TimerBackend {
+    /// Returns this backend if it can be used in this process, or the one
+    /// which is used instead.
+    pub(crate) fn resolve(self) -> TimerBackend {
+        #[cfg(target_os = "linux")]
+        if self == TimerBackend::PerfEvent {
+            if let Err(err) = perf_event::probe() {
+                log::warn!(
+                    "perf_event_open is not available ({}), falling back to the process timer",
+                    err
+                );
+                return TimerBackend::Process;
+            }
+        }
+
+        self
+    }
+}
+
+enum Source {
+    Process,
+    #[cfg(target_os = "linux")]
+    ProcessTimer(ProcessTimer),
+    #[cfg(target_os = "linux")]
+    PerThread(ThreadTimers),
+    #[cfg(target_os = "linux")]
+    WallClock(WallClockTimer),
+    #[cfg(target_os = "linux")]
+    PerfEvent(ThreadTimers),
+}
+
+/// Returns the interval between two samples, in microseconds.
+fn interval_micros(frequency: c_int) -> i64 {
+    1e6 as i64 / i64::from(frequency)
+}
+
+impl Source {
+    fn arm(
+        frequency: c_int,
+        backend: TimerBackend,
+        signal: c_int,
+        jitter: f64,
+    ) -> io::Result<Source> {
+        let source = Source::arm_periodic(frequency, backend, signal, jitter)?;
+
+        // The wall-clock timer draws its intervals itself, the others are
+        // re-armed by the signal handler.
+        #[cfg(target_os = "linux")]
+        let rearmed = backend != TimerBackend::WallClock;
+        #[cfg(not(target_os = "linux"))]
+        let rearmed = true;
+        if jitter > 0.0 && rearmed {
+            #[cfg(target_os = "linux")]
+            let code = signal_code(backend, signal);
+            #[cfg(not(target_os = "linux"))]
+            let code = 0;
+
+            #[cfg(target_os = "linux")]
+            let clock = match backend {
+                TimerBackend::PerThread => jitter::Clock::Thread,
+                _ => jitter::Clock::Process,
+            };
+            #[cfg(not(target_os = "linux"))]
+            let clock = jitter::Clock::Process;
+
+            let interval = Duration::from_micros(interval_micros(frequency) as u64);
+            jitter::enable(interval, jitter, code, clock);
+        }
+
+        Ok(source)
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn arm_periodic(
+        frequency: c_int,
+        backend: TimerBackend,
+        signal: c_int,
+        jitter: f64,
+    ) -> io::Result<Source> {
+        let interval = interval_micros(frequency);
+
+        let source = match backend {
+            TimerBackend::Process => {
+                // `ITIMER_PROF` can only send `SIGPROF`
+                #[cfg(target_os = "linux")]
+                if signal != libc::SIGPROF {
+                    let interval = Duration::from_micros(interval as u64);
+                    return Ok(Source::ProcessTimer(ProcessTimer::new(interval, signal)?));
+                }
+
+                set_itimer_prof(interval, interval)?;
+                Source::Process
+            }
+            #[cfg(target_os = "linux")]
+            TimerBackend::PerThread => Source::PerThread(ThreadTimers::new(
+                Duration::from_micros(interval as u64),
+                signal,
+            )?),
+            #[cfg(target_os = "linux")]
+            TimerBackend::WallClock => Source::WallClock(WallClockTimer::new(
+                Duration::from_micros(interval as u64),
+                signal,
+                jitter,
+            )?),
+            #[cfg(target_os = "linux")]
+            TimerBackend::PerfEvent => Source::PerfEvent(perf_event::new(
+                perf_event::Event::TASK_CLOCK,
+                Duration::from_micros(interval as u64),
+                signal,
+            )?),
+        };
+
+        Ok(source)
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        // the signal handler must not re-arm the timer once it's stopped
+        jitter::disable();
+
+        match self {
+            Source::Process => {
+                if let Err(err) = set_itimer_prof(0, 0) {
+                    log::error!("fail to stop the timer: {}", err);
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Source::ProcessTimer(timer) => timer.stop(),
+            #[cfg(target_os = "linux")]
+            Source::PerThread(timers) => timers.stop(),
+            #[cfg(target_os = "linux")]
+            Source::WallClock(timer) => timer.stop(),
+            #[cfg(target_os = "linux")]
+            Source::PerfEvent(counters) => counters.stop(),
+        }
+    }
+}
+
 pub struct Timer {
     pub frequency: c_int,
+    pub backend: TimerBackend,
+    pub signal: c_int,
+    pub jitter: f64,
     pub start_time: SystemTime,
-    pub start_instant: Instant,
+    /// Time spent active before the last pause.
+    active: Duration,
+    /// When the timer was last armed, or `None` if it's paused.
+    resumed_at: Option<Instant>,
+    source: Option<Source>,
 }
 
 impl Timer {
-    pub fn new(frequency: c_int) -> Timer {
-        let interval = 1e6 as i64 / i64::from(frequency);
-        let it_interval = Timeval {
-            tv_sec: interval / 1e6 as i64,
-            tv_usec: interval % 1e6 as i64,
-        };
-        let it_value = it_interval.clone();
-
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
-        };
+    pub fn new(
+        frequency: c_int,
+        backend: TimerBackend,
+        signal: c_int,
+        jitter: f64,
+    ) -> io::Result<Timer> {
+        let source = Source::arm(frequency, backend, signal, jitter)?;
 
-        Timer {
+        Ok(Timer {
             frequency,
+            backend,
+            signal,
+            jitter,
             start_time: SystemTime::now(),
-            start_instant: Instant::now(),
+            active: Duration::ZERO,
+            resumed_at: Some(Instant::now()),
+            source: Some(source),
+        })
+    }
+
+    /// Disarms the timer. The time until `resume` is not counted in the
+    /// duration of the reports.
+    pub fn pause(&mut self) {
+        if let Some(resumed_at) = self.resumed_at.take() {
+            drop(self.source.take());
+            self.active += resumed_at.elapsed();
+        }
+    }
+
+    /// Re-arms the timer after `pause`.
+    pub fn resume(&mut self) -> io::Result<()> {
+        if self.resumed_at.is_none() {
+            self.source = Some(Source::arm(
+                self.frequency,
+                self.backend,
+                self.signal,
+                self.jitter,
+            )?);
+            self.resumed_at = Some(Instant::now());
         }
+
+        Ok(())
     }
 
     /// Returns a `ReportTiming` struct having this timer's frequency and start
-    /// time; and the time elapsed since its creation as duration.
+    /// time; and the time it has been active since its creation as duration.
     pub fn timing(&self) -> ReportTiming {
         ReportTiming {
             frequency: self.frequency,
+            backend: self.backend,
             start_time: self.start_time,
-            duration: self.start_instant.elapsed(),
+            duration: self.active + self.resumed_at.map_or(Duration::ZERO, |t| t.elapsed()),
         }
     }
+
+    /// Returns the same `ReportTiming` as `timing`, and starts a new interval
+    /// from now on.
+    pub fn reset(&mut self) -> ReportTiming {
+        let now = Instant::now();
+        let timing = ReportTiming {
+            duration: self.active + self.resumed_at.map_or(Duration::ZERO, |t| now - t),
+            ..self.timing()
+        };
+
+        self.start_time = SystemTime::now();
+        self.active = Duration::ZERO;
+        if self.resumed_at.is_some() {
+            self.resumed_at = Some(now);
+        }
+        timing
+    }
 }
 
-impl Drop for Timer {
+/// The ids of the threads the profiler runs itself, which the timers of the
+/// `PerThread`, `WallClock` and `PerfEvent` backends leave out.
+#[cfg(target_os = "linux")]
+static OWN_THREADS: std::sync::Mutex<Vec<libc::pid_t>> = std::sync::Mutex::new(Vec::new());
+
+/// Marks the current thread as a thread of the profiler, until the returned
+/// guard is dropped.
+pub(crate) fn register_own_thread() -> OwnThread {
+    #[cfg(target_os = "linux")]
+    {
+        let tid = unsafe { libc::gettid() };
+        let mut threads = OWN_THREADS.lock().unwrap_or_else(|err| err.into_inner());
+        threads.push(tid);
+        OwnThread(tid)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    OwnThread(0)
+}
+
+/// Tells whether `tid` is a thread of the profiler.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_own_thread(tid: libc::pid_t) -> bool {
+    let threads = OWN_THREADS.lock().unwrap_or_else(|err| err.into_inner());
+    threads.contains(&tid)
+}
+
+/// Unmarks the thread of the profiler when it's dropped.
+pub(crate) struct OwnThread(#[cfg_attr(not(target_os = "linux"), allow(dead_code))] libc::pid_t);
+
+impl Drop for OwnThread {
     fn drop(&mut self) {
-        let it_interval = Timeval {
-            tv_sec: 0,
-            tv_usec: 0,
-        };
-        let it_value = it_interval.clone();
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
-        };
+        #[cfg(target_os = "linux")]
+        {
+            let mut threads = OWN_THREADS.lock().unwrap_or_else(|err| err.into_inner());
+            threads.retain(|tid| *tid != self.0);
+        }
+    }
+}
+
+// `si_code` values of the kernel, which are not exported by `libc`.
+#[cfg(target_os = "linux")]
+const POLL_IN: c_int = 1;
+#[cfg(target_os = "linux")]
+const SI_KERNEL: c_int = 0x80;
+#[cfg(target_os = "linux")]
+const SI_TIMER: c_int = -2;
+#[cfg(target_os = "linux")]
+const SI_QUEUE: c_int = -1;
+
+/// Returns the `si_code` of the signals sent by the timer of `backend`.
+#[cfg(target_os = "linux")]
+pub fn signal_code(backend: TimerBackend, signal: c_int) -> c_int {
+    match backend {
+        TimerBackend::Process if signal == libc::SIGPROF => SI_KERNEL,
+        TimerBackend::Process | TimerBackend::PerThread => SI_TIMER,
+        TimerBackend::WallClock => SI_QUEUE,
+        TimerBackend::PerfEvent => POLL_IN,
     }
 }
 
+/// Tells whether a signal was sent by a timer of the profiler whose signals
+/// have the `si_code` of `code`, or by someone else using the same signal.
+///
+/// `ITIMER_PROF` can't be told apart from another process-wide `ITIMER_PROF`
+/// timer, but there can only be one of them in a process anyway. The same
+/// goes for the signals of `perf_event_open` and of other files in async mode.
+#[cfg(target_os = "linux")]
+pub fn is_own_signal(siginfo: &libc::siginfo_t, code: c_int) -> bool {
+    if siginfo.si_code != code {
+        return false;
+    }
+    match code {
+        SI_TIMER | SI_QUEUE => unsafe { siginfo.si_value().sival_ptr == posix::marker() },
+        _ => true,
+    }
+}
+
+/// The layout of `siginfo_t` in the kernel, whose fields for the signals of
+/// timers and files are not exposed by `libc`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct KernelSiginfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    fields: SiginfoFields,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+union SiginfoFields {
+    timer: SiginfoTimer,
+    poll: SiginfoPoll,
+    queue: SiginfoQueue,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SiginfoTimer {
+    timer_id: c_int,
+    overrun: c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SiginfoPoll {
+    band: libc::c_long,
+    fd: c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SiginfoQueue {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    value: libc::sigval,
+}
+
+/// Re-arms the timer which sent the signal of `siginfo` with a random
+/// interval, if the profiler has a jitter. It's called in the signal handler.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub fn rearm(siginfo: *const libc::siginfo_t) {
+    let rearm = match jitter::Rearm::start() {
+        Some(rearm) => rearm,
+        None => return,
+    };
+    let interval = rearm.interval.as_micros() as i64;
+
+    #[cfg(target_os = "linux")]
+    {
+        if siginfo.is_null() || !is_own_signal(unsafe { &*siginfo }, rearm.code) {
+            return;
+        }
+
+        let fields = unsafe { &(*(siginfo as *const KernelSiginfo)).fields };
+        // the timer was armed with the same values before, so it can't fail
+        match rearm.code {
+            SI_KERNEL => {
+                let _ = set_itimer_prof(rearm.value().as_micros() as i64, interval);
+            }
+            SI_TIMER => {
+                let timer_id = unsafe { fields.timer.timer_id };
+                posix::rearm(timer_id, rearm.value(), rearm.interval);
+            }
+            // the counters are not driven by the ticks of the scheduler
+            POLL_IN => perf_event::set_period(unsafe { fields.poll.fd }, rearm.next),
+            _ => {}
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = set_itimer_prof(rearm.value().as_micros() as i64, interval);
+}
+
 /// Timing metadata for a collected report.
 #[derive(Clone)]
 pub struct ReportTiming {
     /// Frequency at which samples were collected.
     pub frequency: i32,
+    /// Source of the profiling signal, which tells whether the samples
+    /// measure cpu time or wall-clock time.
+    pub backend: TimerBackend,
     /// Collection start time.
     pub start_time: SystemTime,
-    /// Collection duration.
+    /// Collection duration, not including the time the profiler was paused.
     pub duration: Duration,
 }
 
+impl ReportTiming {
+    /// Returns the mean interval between two samples, which is the time
+    /// represented by each of them.
+    pub fn period(&self) -> Duration {
+        Duration::from_micros(interval_micros(self.frequency) as u64)
+    }
+}
+
 impl Default for ReportTiming {
     fn default() -> Self {
         Self {
             frequency: 1,
+            backend: TimerBackend::default(),
             start_time: SystemTime::UNIX_EPOCH,
             duration: Default::default(),
         }
@@ -0,0 +1,249 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+// The configuration of the running timer, which is read by the signal handler.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INTERVAL_NANOS: AtomicU64 = AtomicU64::new(0);
+static SPREAD_NANOS: AtomicU64 = AtomicU64::new(0);
+static SIGNAL_CODE: AtomicI32 = AtomicI32::new(0);
+static CLOCK: AtomicU8 = AtomicU8::new(Clock::Process as u8);
+
+// The number of signal handlers which are re-arming a timer.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// The next expiry of the process-wide timer on the cpu clock of the process,
+// in nanoseconds, or 0 before the first one.
+static PROCESS_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+// Incremented every time the jitter is enabled, which invalidates the
+// deadlines of the threads.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // The generation and the next expiry of the timer of the current thread,
+    // on its cpu clock. It has a const initializer and no destructor, so that
+    // it can be used in the signal handler.
+    static THREAD_DEADLINE: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+static RANDOM: AtomicU64 = AtomicU64::new(0);
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Returns a random number. It's lock-free, so it can be called in the signal
+/// handler.
+fn random() -> u64 {
+    // splitmix64, whose state is only advanced by a constant, so that the
+    // threads never draw the same number
+    let mut z = RANDOM
+        .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Draws an interval uniformly between `interval - spread` and
+/// `interval + spread`, so that its mean is `interval`.
+fn draw(interval: u64, spread: u64) -> Duration {
+    let offset = random() % (2 * spread + 1);
+    Duration::from_nanos(interval - spread + offset)
+}
+
+/// Returns the spread of the intervals around `interval` for the `jitter`
+/// ratio. The intervals are at least a microsecond, as a timer armed with
+/// zero is stopped.
+fn spread(interval: Duration, jitter: f64) -> u64 {
+    let interval = interval.as_nanos() as u64;
+    let spread = (interval as f64 * jitter.clamp(0.0, 1.0)) as u64;
+    spread.min(interval.saturating_sub(1000))
+}
+
+/// Returns the next interval of a timer which isn't re-armed in the signal
+/// handler.
+pub fn next(interval: Duration, jitter: f64) -> Duration {
+    draw(interval.as_nanos() as u64, spread(interval, jitter))
+}
+
+/// Makes the signal handler re-arm the timer on `clock` whose signals have the
+/// `si_code` of `code` with random intervals.
+pub fn enable(interval: Duration, jitter: f64, code: c_int, clock: Clock) {
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    RANDOM.store(seed, Ordering::Relaxed);
+
+    INTERVAL_NANOS.store(interval.as_nanos() as u64, Ordering::SeqCst);
+    SPREAD_NANOS.store(spread(interval, jitter), Ordering::SeqCst);
+    SIGNAL_CODE.store(code, Ordering::SeqCst);
+    CLOCK.store(clock as u8, Ordering::SeqCst);
+    PROCESS_DEADLINE.store(0, Ordering::SeqCst);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops re-arming the timer. When it returns, no signal handler is re-arming
+/// it anymore, so it can be stopped for good.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+    while ACTIVE.load(Ordering::SeqCst) != 0 {
+        std::hint::spin_loop();
+    }
+}
+
+/// A random interval drawn in the signal handler. The timer must only be
+/// re-armed while it's alive.
+pub struct Rearm {
+    /// The `si_code` of the signals of the timer.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub code: c_int,
+    pub interval: Duration,
+    pub next: Duration,
+    clock: Clock,
+}
+
+impl Rearm {
+    /// Returns the next interval if the timer is to be re-armed.
+    pub fn start() -> Option<Rearm> {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        if !ENABLED.load(Ordering::SeqCst) {
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        let interval = INTERVAL_NANOS.load(Ordering::SeqCst);
+        let spread = SPREAD_NANOS.load(Ordering::SeqCst);
+        Some(Rearm {
+            code: SIGNAL_CODE.load(Ordering::SeqCst),
+            interval: Duration::from_nanos(interval),
+            next: draw(interval, spread),
+            clock: Clock::from_u8(CLOCK.load(Ordering::SeqCst)),
+        })
+    }
+}
+
+/// The cpu clock which drives a timer.
+#[derive(Clone, Copy)]
+pub enum Clock {
+    Process,
+    #[cfg(target_os = "linux")]
+    Thread,
+}
+
+impl Clock {
+    fn from_u8(clock: u8) -> Clock {
+        match clock {
+            #[cfg(target_os = "linux")]
+            clock if clock == Clock::Thread as u8 => Clock::Thread,
+            _ => Clock::Process,
+        }
+    }
+
+    fn now(self) -> u64 {
+        let id = match self {
+            Clock::Process => libc::CLOCK_PROCESS_CPUTIME_ID,
+            #[cfg(target_os = "linux")]
+            Clock::Thread => libc::CLOCK_THREAD_CPUTIME_ID,
+        };
+
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(id, &mut now) };
+        now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+    }
+}
+
+impl Rearm {
+    /// Returns the time until the next expiry of the timer.
+    ///
+    /// The cpu timers only expire on a tick of the scheduler, so they are
+    /// always a bit late. The next expiry is drawn from the previous one rather
+    /// than from now, so that the delays don't add up and the mean interval is
+    /// kept.
+    pub fn value(&self) -> Duration {
+        let clock = self.clock;
+        let now = clock.now();
+        let next = self.next.as_nanos() as u64;
+        let interval = self.interval.as_nanos() as u64;
+
+        let schedule = |previous: u64| {
+            let deadline = previous + next;
+            // When the timer is late by a whole interval, e.g. because the
+            // process was descheduled, the missed samples are skipped instead
+            // of being taken in a burst.
+            if previous == 0 || deadline + interval < now {
+                now + next
+            } else {
+                deadline
+            }
+        };
+
+        let deadline = match clock {
+            Clock::Process => {
+                // The handlers of several threads may run at the same time, so
+                // the deadline is advanced with a compare-and-swap, and each
+                // one schedules from the deadline the previous one stored.
+                let advance = |previous| Some(schedule(previous));
+                let previous = PROCESS_DEADLINE
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, advance)
+                    .unwrap_or_else(|previous| previous);
+                schedule(previous)
+            }
+            #[cfg(target_os = "linux")]
+            Clock::Thread => {
+                let generation = GENERATION.load(Ordering::SeqCst);
+                let previous = match THREAD_DEADLINE.try_with(Cell::get) {
+                    Ok((thread_generation, deadline)) if thread_generation == generation => {
+                        deadline
+                    }
+                    _ => 0,
+                };
+                let deadline = schedule(previous);
+                let _ = THREAD_DEADLINE.try_with(|cell| cell.set((generation, deadline)));
+                deadline
+            }
+        };
+
+        // the timer is stopped if it's armed with zero
+        Duration::from_nanos(deadline.saturating_sub(now).max(1000))
+    }
+}
+
+impl Drop for Rearm {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_around_mean() {
+        let interval = Duration::from_millis(10);
+        let count = 100_000;
+
+        let mut total = Duration::ZERO;
+        for _ in 0..count {
+            let next = next(interval, 0.5);
+            assert!(next >= Duration::from_millis(5) && next <= Duration::from_millis(15));
+            total += next;
+        }
+
+        let mean = total / count;
+        assert!(
+            mean > Duration::from_micros(9_950) && mean < Duration::from_micros(10_050),
+            "mean interval {:?}",
+            mean
+        );
+        assert_eq!(next(interval, 0.0), interval);
+    }
+}
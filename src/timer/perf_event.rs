@@ -0,0 +1,270 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::time::Duration;
+
+use super::thread_cpu::{list_threads, ThreadSources, ThreadTimers};
+
+/// The fields of `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER1`, which
+/// `libc` doesn't define. The kernel accepts this older size.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+}
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+
+// bits of `PerfEventAttr::flags`
+const ATTR_DISABLED: u64 = 1 << 0;
+const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+// _IO('$', 0)
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+
+// _IOW('$', 4, u64)
+#[cfg(not(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+)))]
+const PERF_EVENT_IOC_PERIOD: libc::c_ulong = 0x4008_2404;
+#[cfg(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+))]
+const PERF_EVENT_IOC_PERIOD: libc::c_ulong = 0x8008_2404;
+
+// `libc` doesn't export them for every target.
+const F_SETSIG: c_int = 10;
+const F_SETOWN_EX: c_int = 15;
+const F_OWNER_TID: c_int = 0;
+
+#[repr(C)]
+struct FOwnerEx {
+    type_: c_int,
+    pid: libc::pid_t,
+}
+
+/// The event counted by the kernel, which sends a signal every time it
+/// overflows the sampling period. Only the cpu clock of the thread is used
+/// for now, but a hardware event, such as the number of cycles, only differs
+/// in its type and config.
+#[derive(Clone, Copy)]
+pub struct Event {
+    type_: u32,
+    config: u64,
+}
+
+impl Event {
+    /// The cpu time of the thread, in nanoseconds.
+    pub const TASK_CLOCK: Event = Event {
+        type_: PERF_TYPE_SOFTWARE,
+        config: PERF_COUNT_SW_TASK_CLOCK,
+    };
+
+    fn open(&self, tid: libc::pid_t, period: u64) -> io::Result<OwnedFd> {
+        let attr = PerfEventAttr {
+            type_: self.type_,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: self.config,
+            sample_period: period,
+            // the kernel is only allowed to profile user space with the
+            // default `perf_event_paranoid`
+            flags: ATTR_DISABLED | ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV,
+            wakeup_events: 1,
+            ..Default::default()
+        };
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                tid,
+                -1,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+    }
+}
+
+/// Tells whether `perf_event_open` can be used by this process, which depends
+/// on the kernel, `perf_event_paranoid` and the seccomp policy.
+pub fn probe() -> io::Result<()> {
+    let tid = unsafe { libc::gettid() };
+    // the counter is disabled, so the period doesn't matter
+    Event::TASK_CLOCK.open(tid, 1_000_000_000).map(drop)
+}
+
+/// The counter of a thread.
+struct Counter {
+    // the counter is disabled when it's closed
+    _fd: OwnedFd,
+    buffer: *mut c_void,
+    buffer_size: usize,
+}
+
+impl Counter {
+    fn new(event: Event, tid: libc::pid_t, signal: c_int, period: u64) -> io::Result<Counter> {
+        let fd = event.open(tid, period)?;
+        let raw = fd.as_raw_fd();
+
+        // The kernel only notifies the owner of the file when it writes into a
+        // ring buffer. A read-only one is overwritten, so it never fills up,
+        // and its records are never read.
+        let buffer_size = 2 * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let buffer = unsafe {
+            libc::mmap(
+                null_mut(),
+                buffer_size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                raw,
+                0,
+            )
+        };
+        if buffer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let counter = Counter {
+            _fd: fd,
+            buffer,
+            buffer_size,
+        };
+
+        let owner = FOwnerEx {
+            type_: F_OWNER_TID,
+            pid: tid,
+        };
+        unsafe {
+            if libc::fcntl(raw, libc::F_SETFL, libc::O_ASYNC) != 0
+                || libc::fcntl(raw, F_SETSIG, signal) != 0
+                || libc::fcntl(raw, F_SETOWN_EX, &owner as *const FOwnerEx) != 0
+                || libc::ioctl(raw, PERF_EVENT_IOC_ENABLE, 0) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(counter)
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.buffer, self.buffer_size) };
+    }
+}
+
+/// Sets the period of the counter `fd` to `period` of its event. It's
+/// async-signal-safe.
+pub fn set_period(fd: c_int, period: Duration) {
+    let period = period.as_nanos().max(1) as u64;
+    unsafe { libc::ioctl(fd, PERF_EVENT_IOC_PERIOD, &period as *const u64) };
+}
+
+struct Counters {
+    event: Event,
+    period: u64,
+    signal: c_int,
+    // the counter of every thread, with the start time of the thread
+    counters: HashMap<libc::pid_t, (u64, Counter)>,
+}
+
+// The buffers of the counters are never accessed, only unmapped.
+unsafe impl Send for Counters {}
+
+impl ThreadSources for Counters {
+    fn rescan(&mut self, skip: libc::pid_t) {
+        let threads = list_threads();
+
+        // a thread whose id was reused gets a new counter
+        self.counters
+            .retain(|tid, (start_time, _)| threads.get(tid) == Some(start_time));
+
+        for (tid, start_time) in threads {
+            if tid == skip || self.counters.contains_key(&tid) {
+                continue;
+            }
+            // the thread may have exited since it was listed, so the failure is ignored
+            if let Ok(counter) = Counter::new(self.event, tid, self.signal, self.period) {
+                self.counters.insert(tid, (start_time, counter));
+            }
+        }
+    }
+}
+
+/// Creates a counter of `event` for every thread, which sends `signal` every
+/// `interval` of the event.
+pub fn new(event: Event, interval: Duration, signal: c_int) -> io::Result<ThreadTimers> {
+    ThreadTimers::spawn(Counters {
+        event,
+        period: interval.as_nanos().max(1) as u64,
+        signal,
+        counters: HashMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_signal(_: c_int) {
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn counter_sends_signal() {
+        if let Err(err) = probe() {
+            eprintln!("perf_event_open is not available: {}", err);
+            return;
+        }
+
+        // a real-time signal which isn't used by the other tests
+        let signal = libc::SIGRTMIN() + 4;
+        unsafe { libc::signal(signal, count_signal as *const () as libc::sighandler_t) };
+
+        let tid = unsafe { libc::gettid() };
+        let counter = Counter::new(Event::TASK_CLOCK, tid, signal, 1_000_000).unwrap();
+        let start = std::time::Instant::now();
+        while SIGNALS.load(Ordering::SeqCst) == 0 && start.elapsed() < Duration::from_secs(5) {
+            std::hint::spin_loop();
+        }
+        drop(counter);
+        // a signal may still be pending
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+
+        assert!(SIGNALS.load(Ordering::SeqCst) > 0);
+    }
+}
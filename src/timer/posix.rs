@@ -0,0 +1,114 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::time::Duration;
+
+// `libc` only exposes it for glibc, but the kernel interface is the same on musl.
+const SIGEV_THREAD_ID: c_int = 4;
+
+static MARKER: u8 = 0;
+
+/// The value carried by the signals of the timers created here, and of the
+/// wall-clock timer, which tells them apart from the signals sent by other
+/// timers in the process.
+pub fn marker() -> *mut c_void {
+    &MARKER as *const u8 as *mut c_void
+}
+
+/// Creates and arms a timer on `clock`, which sends `signal` every `interval`.
+/// The signal is delivered to the thread `tid` if it's given, or to the
+/// process otherwise.
+pub fn create(
+    clock: libc::clockid_t,
+    tid: Option<libc::pid_t>,
+    signal: c_int,
+    interval: Duration,
+) -> Option<libc::timer_t> {
+    let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+    match tid {
+        Some(tid) => {
+            event.sigev_notify = SIGEV_THREAD_ID;
+            event.sigev_notify_thread_id = tid;
+        }
+        None => event.sigev_notify = libc::SIGEV_SIGNAL,
+    }
+    event.sigev_signo = signal;
+    event.sigev_value.sival_ptr = marker();
+
+    let mut timer: libc::timer_t = null_mut();
+    if unsafe { libc::timer_create(clock, &mut event, &mut timer) } != 0 {
+        return None;
+    }
+
+    let interval = libc::timespec {
+        tv_sec: interval.as_secs() as libc::time_t,
+        tv_nsec: interval.subsec_nanos() as libc::c_long,
+    };
+    let spec = libc::itimerspec {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::timer_settime(timer, 0, &spec, null_mut()) } != 0 {
+        unsafe { libc::timer_delete(timer) };
+        return None;
+    }
+
+    Some(timer)
+}
+
+/// Makes the timer with the kernel id `timer_id` expire after `value`, and
+/// then every `interval`. The id is the one carried by the signals of the
+/// timer, which is not always the `timer_t` of the C library, so the system
+/// call is used directly. It's async-signal-safe.
+pub fn rearm(timer_id: c_int, value: Duration, interval: Duration) {
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        },
+        it_value: libc::timespec {
+            tv_sec: value.as_secs() as libc::time_t,
+            tv_nsec: value.subsec_nanos() as libc::c_long,
+        },
+    };
+    // the timer may have been deleted since it sent the signal
+    unsafe {
+        libc::syscall(
+            libc::SYS_timer_settime,
+            timer_id,
+            0,
+            &spec as *const libc::itimerspec,
+            null_mut::<libc::itimerspec>(),
+        )
+    };
+}
+
+/// A process-wide timer on `CLOCK_PROCESS_CPUTIME_ID`. It's used instead of
+/// `ITIMER_PROF` when the profiling signal is not `SIGPROF`.
+pub struct ProcessTimer(Option<libc::timer_t>);
+
+// `timer_t` is a raw pointer, but it is only an identifier for the kernel.
+unsafe impl Send for ProcessTimer {}
+
+impl ProcessTimer {
+    pub fn new(interval: Duration, signal: c_int) -> std::io::Result<ProcessTimer> {
+        match create(libc::CLOCK_PROCESS_CPUTIME_ID, None, signal, interval) {
+            Some(timer) => Ok(ProcessTimer(Some(timer))),
+            None => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    /// Deletes the timer.
+    pub fn stop(&mut self) {
+        if let Some(timer) = self.0.take() {
+            unsafe { libc::timer_delete(timer) };
+        }
+    }
+}
+
+impl Drop for ProcessTimer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::{is_own_thread, posix, register_own_thread};
+
+/// How often the thread list is scanned again to arm timers for new threads
+/// and release the ones of exited threads.
+pub const RESCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the ids of the threads in the current process, with their start
+/// time, which tells a thread apart from an earlier one with the same id. The
+/// threads of the profiler itself are left out.
+pub fn list_threads() -> HashMap<libc::pid_t, u64> {
+    match std::fs::read_dir("/proc/self/task") {
+        Ok(dir) => dir
+            .filter_map(|entry| {
+                let tid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+                if is_own_thread(tid) {
+                    return None;
+                }
+                // the thread may have exited since it was listed
+                let stat = std::fs::read_to_string(format!("/proc/self/task/{}/stat", tid)).ok()?;
+                Some((tid, start_time(&stat)?))
+            })
+            .collect(),
+        Err(err) => {
+            log::error!("fail to list threads: {}", err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Returns the start time of a thread, in clock ticks since the boot, from its
+/// `stat` file. It's the 22nd field, and the 2nd one is the name of the
+/// thread, which may contain spaces and parentheses.
+fn start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    // the fields after the name start with the 3rd one
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+/// Builds the id of the `CLOCK_THREAD_CPUTIME_ID` clock of another thread. This
+/// is the same encoding as `pthread_getcpuclockid` uses in glibc.
+fn thread_cpu_clock(tid: libc::pid_t) -> libc::clockid_t {
+    const CPUCLOCK_PERTHREAD_MASK: libc::clockid_t = 4;
+    const CPUCLOCK_SCHED: libc::clockid_t = 2;
+
+    (!(tid as libc::clockid_t) << 3) | CPUCLOCK_PERTHREAD_MASK | CPUCLOCK_SCHED
+}
+
+/// A set of per-thread signal sources, which is kept in sync with the threads
+/// of the process by `ThreadTimers`.
+pub trait ThreadSources: Send + 'static {
+    /// Arms a source for every thread that doesn't have one, except `skip`,
+    /// and releases the ones of threads which have exited.
+    fn rescan(&mut self, skip: libc::pid_t);
+}
+
+struct Timers {
+    interval: Duration,
+    signal: c_int,
+    // the timer of every thread, with the start time of the thread
+    timers: HashMap<libc::pid_t, (u64, libc::timer_t)>,
+}
+
+// `timer_t` is a raw pointer, but it is only an identifier for the kernel.
+unsafe impl Send for Timers {}
+
+impl Timers {
+    fn new(interval: Duration, signal: c_int) -> Self {
+        Timers {
+            interval,
+            signal,
+            timers: HashMap::new(),
+        }
+    }
+}
+
+impl ThreadSources for Timers {
+    fn rescan(&mut self, skip: libc::pid_t) {
+        let threads = list_threads();
+
+        // a thread whose id was reused gets a new timer
+        self.timers.retain(|tid, (start_time, timer)| {
+            let alive = threads.get(tid) == Some(start_time);
+            if !alive {
+                unsafe { libc::timer_delete(*timer) };
+            }
+            alive
+        });
+
+        for (tid, start_time) in threads {
+            if tid == skip || self.timers.contains_key(&tid) {
+                continue;
+            }
+            // the thread may have exited since it was listed, so the failure is ignored
+            let clock = thread_cpu_clock(tid);
+            if let Some(timer) = posix::create(clock, Some(tid), self.signal, self.interval) {
+                self.timers.insert(tid, (start_time, timer));
+            }
+        }
+    }
+}
+
+impl Drop for Timers {
+    fn drop(&mut self) {
+        for (_, timer) in self.timers.values() {
+            unsafe { libc::timer_delete(*timer) };
+        }
+    }
+}
+
+/// A set of per-thread signal sources, kept in sync with the threads of the
+/// process by a background thread. They are CPU timers by default.
+pub struct ThreadTimers {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ThreadTimers {
+    pub fn new(interval: Duration, signal: c_int) -> io::Result<ThreadTimers> {
+        ThreadTimers::spawn(Timers::new(interval, signal))
+    }
+
+    pub fn spawn<T: ThreadSources>(mut timers: T) -> io::Result<ThreadTimers> {
+        // Arm the existing threads right away, so that they are sampled from
+        // the very beginning.
+        timers.rescan(0);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-timer".to_owned())
+                .spawn(move || {
+                    let _own_thread = register_own_thread();
+                    let tid = unsafe { libc::gettid() };
+                    loop {
+                        std::thread::park_timeout(RESCAN_INTERVAL);
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        timers.rescan(tid);
+                    }
+                })?
+        };
+
+        Ok(ThreadTimers {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stops the background thread, and deletes all timers.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ThreadTimers {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_current_thread() {
+        let tid = unsafe { libc::gettid() };
+        assert!(list_threads().contains_key(&tid));
+    }
+
+    #[test]
+    fn list_without_own_threads() {
+        std::thread::spawn(|| {
+            let tid = unsafe { libc::gettid() };
+            let own_thread = register_own_thread();
+            assert!(!list_threads().contains_key(&tid));
+            drop(own_thread);
+            assert!(list_threads().contains_key(&tid));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn start_time_of_stat() {
+        let stat = "42 (a) b (c) R 1 42 42 0 -1 4194560 100 0 0 0 5 3 0 0 20 0 2 0 12345 0 0";
+        assert_eq!(start_time(stat), Some(12345));
+        assert_eq!(start_time("42 (truncated"), None);
+    }
+
+    #[test]
+    fn thread_cpu_clock_of_current_thread() {
+        let mut own = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let mut encoded = own;
+
+        let tid = unsafe { libc::gettid() };
+        unsafe {
+            assert_eq!(libc::clock_gettime(thread_cpu_clock(tid), &mut encoded), 0);
+            assert_eq!(
+                libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut own),
+                0
+            );
+        }
+
+        // both clocks measure the cpu time of the current thread
+        let encoded = Duration::new(encoded.tv_sec as u64, encoded.tv_nsec as u32);
+        let own = Duration::new(own.tv_sec as u64, own.tv_nsec as u32);
+        assert!(encoded <= own);
+        assert!(own - encoded < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::thread_cpu::{list_threads, RESCAN_INTERVAL};
+use super::{jitter, posix, register_own_thread, KernelSiginfo, SiginfoQueue, SI_QUEUE};
+
+/// Sends `signal` to the thread `tid` with the marker of the profiler, as
+/// `tgkill` would without it.
+fn queue_signal(pid: libc::pid_t, uid: libc::uid_t, tid: libc::pid_t, signal: c_int) {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let kernel = unsafe { &mut *(&mut info as *mut libc::siginfo_t as *mut KernelSiginfo) };
+    kernel.si_signo = signal;
+    kernel.si_code = SI_QUEUE;
+    kernel.fields.queue = SiginfoQueue {
+        pid,
+        uid,
+        value: libc::sigval {
+            sival_ptr: posix::marker(),
+        },
+    };
+
+    // the thread may have exited since it was listed, so the error is ignored
+    unsafe {
+        libc::syscall(
+            libc::SYS_rt_tgsigqueueinfo,
+            pid,
+            tid,
+            signal,
+            &info as *const libc::siginfo_t,
+        )
+    };
+}
+
+/// Sends the profiling signal to every thread of the process at a fixed
+/// real-time interval, no matter whether the thread is running or blocked.
+pub struct WallClockTimer {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WallClockTimer {
+    pub fn new(interval: Duration, signal: c_int, jitter: f64) -> std::io::Result<WallClockTimer> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("pprof-timer".to_owned())
+                .spawn(move || run(interval, jitter, signal, &stop))?
+        };
+
+        Ok(WallClockTimer {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Stops the background thread.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for WallClockTimer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(interval: Duration, jitter: f64, signal: c_int, stop: &AtomicBool) {
+    let _own_thread = register_own_thread();
+    let pid = unsafe { libc::getpid() };
+    let uid = unsafe { libc::getuid() };
+    let own_tid = unsafe { libc::gettid() };
+
+    let mut threads = list_threads();
+    let mut listed_at = Instant::now();
+    // `Instant` is based on `CLOCK_MONOTONIC`, and the deadlines are absolute, so
+    // the time spent on sending the signals doesn't accumulate as a drift.
+    let mut deadline = Instant::now() + jitter::next(interval, jitter);
+
+    loop {
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            std::thread::park_timeout(deadline - now);
+        }
+
+        if listed_at.elapsed() >= RESCAN_INTERVAL {
+            threads = list_threads();
+            listed_at = Instant::now();
+        }
+
+        for tid in threads.keys() {
+            if *tid != own_tid {
+                queue_signal(pid, uid, *tid, signal);
+            }
+        }
+
+        let next = jitter::next(interval, jitter);
+        deadline += next;
+        // If this thread was not scheduled for a long time, skip the missed
+        // ticks instead of sending a burst of signals.
+        let now = Instant::now();
+        if deadline < now {
+            deadline = now + next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::{is_own_signal, signal_code, TimerBackend};
+    use std::sync::atomic::AtomicUsize;
+
+    // a real-time signal which isn't used by the other tests
+    fn test_signal() -> c_int {
+        libc::SIGRTMIN() + 5
+    }
+
+    static OWN_SIGNALS: AtomicUsize = AtomicUsize::new(0);
+    static OTHER_SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_signal(_: c_int, siginfo: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        let code = signal_code(TimerBackend::WallClock, test_signal());
+        if is_own_signal(unsafe { &*siginfo }, code) {
+            OWN_SIGNALS.fetch_add(1, Ordering::SeqCst);
+        } else {
+            OTHER_SIGNALS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn signals_carry_marker() {
+        let signal = test_signal();
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = count_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+
+        let (pid, uid, tid) = unsafe { (libc::getpid(), libc::getuid(), libc::gettid()) };
+        queue_signal(pid, uid, tid, signal);
+        // the same signal without the marker, as another user of it may send
+        unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) };
+
+        let start = Instant::now();
+        while OWN_SIGNALS.load(Ordering::SeqCst) + OTHER_SIGNALS.load(Ordering::SeqCst) < 2
+            && start.elapsed() < Duration::from_secs(5)
+        {
+            std::hint::spin_loop();
+        }
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+
+        assert_eq!(OWN_SIGNALS.load(Ordering::SeqCst), 1);
+        assert_eq!(OTHER_SIGNALS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2025 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Attribution of the samples to Tokio tasks. **only available with `tokio` feature**
+//!
+//! The samples taken while a task is polled carry the id of the task as the
+//! [`TASK_ID`] label, and the name given by [`named`] as the [`TASK_NAME`]
+//! label. They're recorded by the futures wrapped in [`named`], or in
+//! [`tracked`] for the id only, when they're polled, since the signal handler
+//! can't read them from Tokio:
+//!
+//! ```
+//! let runtime = tokio::runtime::Builder::new_multi_thread()
+//!     .on_thread_start(pprof::tokio::register_thread)
+//!     .build()
+//!     .unwrap();
+//!
+//! runtime.block_on(async {
+//!     tokio::spawn(pprof::tokio::named("handle-get", async { 1 + 1 }))
+//!         .await
+//!         .unwrap();
+//!     tokio::spawn(pprof::tokio::tracked(async { 2 + 2 }))
+//!         .await
+//!         .unwrap();
+//! });
+//! ```
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::labels;
+
+/// The key of the label which holds the id of the task.
+pub const TASK_ID: &str = "task_id";
+
+/// The key of the label which holds the name of the task.
+pub const TASK_NAME: &str = "task_name";
+
+/// The task which was running when a sample was taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Task {
+    id: Option<::tokio::task::Id>,
+    // the id of the interned `TASK_NAME` label
+    name: Option<u32>,
+}
+
+impl Task {
+    pub const NONE: Task = Task {
+        id: None,
+        name: None,
+    };
+
+    pub fn labels(&self, registry: &[(String, String)]) -> Vec<(String, String)> {
+        let mut labels = Vec::new();
+        if let Some(id) = self.id {
+            labels.push((TASK_ID.to_owned(), id.to_string()));
+        }
+        if let Some(name) = self.name {
+            labels.push(registry[name as usize].clone());
+        }
+        labels
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ThreadState {
+    id: Option<::tokio::task::Id>,
+    name: Option<u32>,
+}
+
+thread_local! {
+    static STATE: Cell<ThreadState> = const {
+        Cell::new(ThreadState {
+            id: None,
+            name: None,
+        })
+    };
+}
+
+/// Records the bounds of the stack of the current thread, as
+/// [`crate::register_thread`] does. It's meant to be passed to
+/// `tokio::runtime::Builder::on_thread_start`.
+pub fn register_thread() {
+    crate::register_thread();
+}
+
+/// Returns the task running on the current thread. It's called in the signal
+/// handler, so it only reads the state recorded by the wrapped futures.
+pub(crate) fn current() -> Task {
+    match STATE.try_with(Cell::get) {
+        Ok(state) => Task {
+            id: state.id,
+            name: state.name,
+        },
+        Err(_) => Task::NONE,
+    }
+}
+
+/// Names the task which runs `future`, so that its samples carry the
+/// [`TASK_NAME`] label.
+pub fn named<F: Future>(name: &str, future: F) -> Named<F> {
+    Named {
+        name: Some(labels::intern(TASK_NAME, name)),
+        future,
+    }
+}
+
+/// Records the id of the task which runs `future`, so that its samples carry
+/// the [`TASK_ID`] label.
+pub fn tracked<F: Future>(future: F) -> Named<F> {
+    Named { name: None, future }
+}
+
+/// A future which records the id and the name of the task while it's polled.
+/// It is created by [`named`] or [`tracked`].
+pub struct Named<F> {
+    name: Option<u32>,
+    future: F,
+}
+
+/// Restores the previous state when the poll returns or unwinds.
+struct Restore(ThreadState);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        STATE.with(|state| state.set(self.0));
+    }
+}
+
+impl<F: Future> Future for Named<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // `future` is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let previous = STATE.with(Cell::get);
+        let _restore = Restore(previous);
+        STATE.with(|state| {
+            state.set(ThreadState {
+                id: ::tokio::task::try_id(),
+                // a tracked future within a named one keeps its name
+                name: this.name.or(previous.name),
+            })
+        });
+
+        future.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_of_named_future() {
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let (id, task) = runtime.block_on(async {
+            ::tokio::spawn(named("test-task", async {
+                (::tokio::task::id(), current())
+            }))
+            .await
+            .unwrap()
+        });
+
+        assert_eq!(task.id, Some(id));
+        assert_eq!(
+            labels::resolve(|registry| task.labels(registry)),
+            vec![
+                (TASK_ID.to_owned(), id.to_string()),
+                (TASK_NAME.to_owned(), "test-task".to_owned()),
+            ]
+        );
+        assert_eq!(current(), Task::default());
+
+        let (id, task) = runtime.block_on(async {
+            ::tokio::spawn(tracked(async { (::tokio::task::id(), current()) }))
+                .await
+                .unwrap()
+        });
+        assert_eq!(
+            labels::resolve(|registry| task.labels(registry)),
+            vec![(TASK_ID.to_owned(), id.to_string())]
+        );
+
+        // the task isn't known to the signal handler outside of the wrappers
+        let task = runtime.block_on(async { ::tokio::spawn(async { current() }).await.unwrap() });
+        assert_eq!(task, Task::NONE);
+    }
+}